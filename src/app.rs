@@ -1,11 +1,16 @@
-use source::Source;
-use view::View;
+use auth;
+use cache;
+use encoding::{Encoding, ENCODINGS};
+use source::{Error, Source};
+use view::{self, View};
 
 use gtk::*;
 use gtk::prelude::*;
 
 use gdk;
 
+use glib::signal::{signal_handler_block, signal_handler_unblock};
+
 use pango::Style;
 
 use std::path::Path;
@@ -15,9 +20,7 @@ use hyper::Url;
 
 
 const TITLE: &str = "Litepad";
-const H1_SCALE: f64 = 2.;
-const H2_SCALE: f64 = 1.6;
-const H3_SCALE: f64 = 1.2;
+const HEADER_SCALES: [f64; 6] = [2., 1.6, 1.2, 1.1, 1., 0.9];
 
 #[derive(Clone)]
 pub struct App {
@@ -27,16 +30,26 @@ pub struct App {
     pub window: Window,
     pub italic: ToolButton,
     pub bold: ToolButton,
+    pub code: ToolButton,
+    pub blockquote: ToolButton,
     pub h1: ToolButton,
     pub h2: ToolButton,
+    pub heading: ComboBoxText,
+    pub encoding: ComboBoxText,
     pub new: ToolButton,
     pub open: ToolButton,
     pub save: ToolButton,
     pub close: ToolButton,
     pub insert_link: ToolButton,
     pub insert_image: ToolButton,
+    pub insert_rule: ToolButton,
+    pub insert_footnote: ToolButton,
     pub tabs: Notebook,
     pub views: Arc<Mutex<Vec<View>>>,
+    /// The handler applying the level chosen in the heading dropdown, once set up
+    heading_changed: Arc<Mutex<Option<u64>>>,
+    /// The handler applying the encoding chosen in the status bar, once set up
+    encoding_changed: Arc<Mutex<Option<u64>>>,
 }
 impl App {
     pub fn current_view(&self) -> usize {
@@ -45,18 +58,12 @@ impl App {
     /// Set up the app
     pub fn new(builder: Builder) -> App {
         let tags = TextTagTable::new();
-        let h1 = TextTag::new("h1");
-        h1.set_property_weight(700);
-        h1.set_property_scale(H1_SCALE);
-        tags.add(&h1);
-        let h2 = TextTag::new("h2");
-        h2.set_property_weight(700);
-        h2.set_property_scale(H2_SCALE);
-        tags.add(&h2);
-        let h3 = TextTag::new("h3");
-        h3.set_property_weight(700);
-        h3.set_property_scale(H3_SCALE);
-        tags.add(&h3);
+        for (i, scale) in HEADER_SCALES.iter().enumerate() {
+            let header = TextTag::new(format!("h{}", i + 1).as_str());
+            header.set_property_weight(700);
+            header.set_property_scale(*scale);
+            tags.add(&header);
+        }
         let item_start = TextTag::new("item");
         item_start.set_property_weight(700);
         tags.add(&item_start);
@@ -67,13 +74,33 @@ impl App {
         italic.set_property_style(Style::Italic);
         italic.set_property_style_set(true);
         tags.add(&italic);
+        let strikethrough = TextTag::new("strikethrough");
+        strikethrough.set_property_strikethrough(true);
+        tags.add(&strikethrough);
         let link = TextTag::new("link");
         link.set_property_foreground(Some("blue"));
         link.set_property_underline_set(true);
         tags.add(&link);
+        let blockquote = TextTag::new("blockquote");
+        blockquote.set_property_left_margin(24);
+        blockquote.set_property_foreground(Some("dim gray"));
+        tags.add(&blockquote);
+        let footnote = TextTag::new("footnote");
+        footnote.set_property_scale(0.9);
+        tags.add(&footnote);
         let code = TextTag::new("code");
         code.set_property_font(Some("Courier New"));
         tags.add(&code);
+        // HTML is shown as written, in spans within text and in blocks of its own
+        for name in &["html", "html-block"] {
+            let html = TextTag::new(*name);
+            html.set_property_font(Some("Courier New"));
+            html.set_property_foreground(Some("dim gray"));
+            tags.add(&html);
+        }
+        // Mark the line breaks within a paragraph, which look like any other line break
+        tags.add(&TextTag::new("hard-break"));
+        tags.add(&TextTag::new("soft-break"));
         let files: TreeView = builder.get_object("files").unwrap();
         let name = TreeViewColumn::new();
         name.set_title("File");
@@ -81,6 +108,10 @@ impl App {
         let list = ListStore::new(&[Type::String]);
         list.insert_with_values(None, &[0], &[&"test"]);
         files.set_model(&list);
+        let encoding: ComboBoxText = builder.get_object("encoding").unwrap();
+        for choice in &ENCODINGS {
+            encoding.append(choice.id(), &choice.to_string());
+        }
         App {
             tags,
             window: builder.get_object("window").unwrap(),
@@ -88,8 +119,12 @@ impl App {
             file_list: list,
             h1: builder.get_object("h1").unwrap(),
             h2: builder.get_object("h2").unwrap(),
+            heading: builder.get_object("heading").unwrap(),
+            encoding,
             bold: builder.get_object("bold").unwrap(),
             italic: builder.get_object("italic").unwrap(),
+            code: builder.get_object("code").unwrap(),
+            blockquote: builder.get_object("blockquote").unwrap(),
             tabs: builder.get_object("tabs").unwrap(),
             new: builder.get_object("new").unwrap(),
             open: builder.get_object("open").unwrap(),
@@ -97,7 +132,11 @@ impl App {
             close: builder.get_object("close").unwrap(),
             insert_link: builder.get_object("insert-link").unwrap(),
             insert_image: builder.get_object("insert-image").unwrap(),
+            insert_rule: builder.get_object("insert-rule").unwrap(),
+            insert_footnote: builder.get_object("insert-footnote").unwrap(),
             views: Arc::new(Mutex::new(Vec::with_capacity(16))),
+            heading_changed: Arc::new(Mutex::new(None)),
+            encoding_changed: Arc::new(Mutex::new(None)),
         }
     }
     pub fn update_title(&self, view: Option<usize>) {
@@ -107,16 +146,63 @@ impl App {
             self.window.set_title(&format!("{} - {}", title, TITLE));
         }
     }
+    /// Show the heading level of the line the cursor is on in the dropdown
+    ///
+    /// The level isn't applied back to the line, as the cursor can move while the views are
+    /// locked, which applying it would need.
+    pub fn show_heading(&self, level: Option<usize>) {
+        let handler = *self.heading_changed.lock().unwrap();
+        if let Some(handler) = handler {
+            signal_handler_block(&self.heading, handler);
+        }
+        self.heading.set_active(level.unwrap_or(0) as i32);
+        if let Some(handler) = handler {
+            signal_handler_unblock(&self.heading, handler);
+        }
+    }
+    /// Show the encoding of a view, or the current one, in the status bar
+    ///
+    /// The encoding isn't applied back to the view, as while switching tabs the current view is
+    /// still the one being left.
+    pub fn update_encoding(&self, view: Option<usize>) {
+        let encoding = {
+            let views = self.views.lock().unwrap();
+            views
+                .get(view.unwrap_or(self.current_view()))
+                .map(|view| view.format.lock().unwrap().encoding)
+        };
+        if let Some(encoding) = encoding {
+            let handler = *self.encoding_changed.lock().unwrap();
+            if let Some(handler) = handler {
+                signal_handler_block(&self.encoding, handler);
+            }
+            self.encoding.set_active_id(encoding.id());
+            if let Some(handler) = handler {
+                signal_handler_unblock(&self.encoding, handler);
+            }
+        }
+    }
     pub fn open(&self, source: Source) {
         let text = format!("{}", source);
         println!("open {}", text);
-        self.file_list.insert_with_values(None, &[0], &[&text]);
-        let view = View::open(source, &self.tags);
+        let view = View::new(source, &self.tags);
+        let loaded = with_login(&view, || view.load());
+        // Redirects can leave the document somewhere else than it was asked for
+        let found = format!("{}", *view.source.lock().unwrap());
+        self.file_list.insert_with_values(None, &[0], &[&found]);
         view.setup(self);
+        // The tab is kept open so that what went wrong can be shown in it
+        if let Err(err) = loaded {
+            view.show_error(&format!("Couldn't open {}: {}", text, err));
+        }
         {
             let mut views = self.views.lock().unwrap();
             views.push(view);
         };
+        // Switching to the tab showed it before it was among the views, so before the title
+        // could show where the document was found
+        self.update_title(None);
+        self.update_encoding(None);
     }
     pub fn setup(&self) {
         self.tabs.remove_page(None);
@@ -130,20 +216,11 @@ impl App {
         self.tabs
             .connect_drag_data_received(move |_, _, _, _, data, _, _| if let Some(uri) =
                 data.get_uris().into_iter().next() {
-                                            let source = if uri.starts_with("file://") {
-                                                Source::File(Path::new(&uri[7..]).to_owned())
-                                            } else {
-                                                Source::Url(Url::parse(&uri)
-                                                                           .ok()
-                                                                           .expect("Invalid URL"))
-                                            };
-                                            let view =
-                                                View::open(source,
-                                                           &me.tags);
-                                            view.setup(&me);
-                                            {
-                                                let mut views = me.views.lock().unwrap();
-                                                views.push(view);
+                                            if uri.starts_with("file://") {
+                                                me.open(Source::File(Path::new(&uri[7..])
+                                                                         .to_owned()));
+                                            } else if let Ok(url) = Url::parse(&uri) {
+                                                me.open(Source::Url(url));
                                             }
                                         });
         let me = self.clone();
@@ -152,10 +229,10 @@ impl App {
                 let mut views = me.views.lock().unwrap();
                 let index = me.current_view();
                 if let Some(view) = views.get(index) {
-                    me.tabs.remove(&view.window);
+                    me.tabs.remove(&view.page);
                 }
                 if views.len() > 0 {
-                    views.remove(index);
+                    views.remove(index).release();
                 }
             });
         let me = self.clone();
@@ -167,6 +244,7 @@ impl App {
                                      let mut views = me.views.lock().unwrap();
                                      views.push(view);
                                  }
+                                 me.update_encoding(None);
                              });
         let window = self.window.clone();
         let filter2 = filter.clone();
@@ -183,10 +261,11 @@ impl App {
             };
             if source.is_some() {
                 let views = views.lock().unwrap();
-                views[tabs.get_property_page() as usize]
-                    .save(Source::Unknown)
-                    .ok()
-                    .expect("Failed to save");
+                let view = &views[tabs.get_property_page() as usize];
+                if let Err(err) = with_login(view, || view.save(Source::Unknown)) {
+                    let source = view.source.lock().unwrap().clone();
+                    view.show_error(&format!("Couldn't save {}: {}", source, err));
+                }
             } else {
                 let dialog = FileChooserDialog::new(Some("Select a file"),
                                                     Some(&window),
@@ -235,6 +314,10 @@ impl App {
                     let builder = Builder::new_from_string(glade_src);
                     let menu: Menu = builder.get_object("menu").unwrap();
                     let load_url: MenuItem = builder.get_object("load-url").unwrap();
+                    let clear_cache: MenuItem = builder.get_object("clear-cache").unwrap();
+                    clear_cache.connect_activate(|_| {
+                                                     cache::clear().ok();
+                                                 });
                     let me = me.clone();
                     load_url.connect_activate(move |_| {
                         // Build from glade
@@ -245,12 +328,13 @@ impl App {
                         let ok: Button = builder.get_object("ok").unwrap();
                         let dialog2 = dialog.clone();
                         ok.connect_clicked(move |_| {
-                                               me.open(Source::Url(Url::parse(&url.get_text()
-                                                                                   .unwrap()
-                                                                                   .trim())
-                                                                           .unwrap()));
-                                               dialog2.destroy();
-                                           });
+                            let text = url.get_text().unwrap_or_default();
+                            // Leave the dialog open to correct a URL that doesn't parse
+                            if let Ok(url) = Url::parse(text.trim()) {
+                                me.open(Source::Url(url));
+                                dialog2.destroy();
+                            }
+                        });
                         dialog.show_all();
                         dialog.run();
                     });
@@ -313,6 +397,38 @@ impl App {
                                  }
                              });
         let me = self.clone();
+        self.code
+            .connect_clicked(move |_| {
+                                 let views = me.views.lock().unwrap();
+                                 if let Some(view) = views.get(me.current_view()) {
+                                     view.apply_code(&me.tags.lookup("code").unwrap());
+                                 }
+                             });
+        let me = self.clone();
+        self.insert_rule
+            .connect_clicked(move |_| {
+                                 let views = me.views.lock().unwrap();
+                                 if let Some(view) = views.get(me.current_view()) {
+                                     view.insert_rule();
+                                 }
+                             });
+        let me = self.clone();
+        self.insert_footnote
+            .connect_clicked(move |_| {
+                                 let views = me.views.lock().unwrap();
+                                 if let Some(view) = views.get(me.current_view()) {
+                                     view.insert_footnote();
+                                 }
+                             });
+        let me = self.clone();
+        self.blockquote
+            .connect_clicked(move |_| {
+                                 let views = me.views.lock().unwrap();
+                                 if let Some(view) = views.get(me.current_view()) {
+                                     view.toggle_quote();
+                                 }
+                             });
+        let me = self.clone();
         self.h1
             .connect_clicked(move |_| {
                                  let views = me.views.lock().unwrap();
@@ -329,53 +445,59 @@ impl App {
                                  }
                              });
         let me = self.clone();
+        let handler = self.heading
+            .connect_changed(move |heading| {
+                let level = match heading.get_active() {
+                    level if level > 0 => Some(level as usize),
+                    _ => None,
+                };
+                let views = me.views.lock().unwrap();
+                if let Some(view) = views.get(me.current_view()) {
+                    if view.heading() != level {
+                        view.set_heading(level);
+                    }
+                }
+            });
+        *self.heading_changed.lock().unwrap() = Some(handler);
+        let me = self.clone();
         self.tabs
-            .connect_switch_page(move |_, _, id| { me.update_title(Some(id as usize)); });
+            .connect_switch_page(move |_, _, id| {
+                                     me.update_title(Some(id as usize));
+                                     me.update_encoding(Some(id as usize));
+                                 });
+        let me = self.clone();
+        let handler = self.encoding
+            .connect_changed(move |encoding| {
+                let chosen = encoding
+                    .get_active_id()
+                    .and_then(|id| Encoding::from_id(&id));
+                let views = me.views.lock().unwrap();
+                if let (Some(view), Some(chosen)) = (views.get(me.current_view()), chosen) {
+                    view.set_encoding(chosen);
+                }
+            });
+        *self.encoding_changed.lock().unwrap() = Some(handler);
         let me = self.clone();
         self.insert_image
             .connect_clicked(move |_| {
-                // Build from glade
-                let builder = Builder::new_from_string(url_dialog_src);
-                let url: Entry = builder.get_object("url").unwrap();
-                let dialog: Dialog = builder.get_object("dialog").unwrap();
                 let me = me.clone();
-                let ok: Button = builder.get_object("ok").unwrap();
-                let dialog2 = dialog.clone();
-                ok.connect_clicked(move |_| {
-
+                view::image_dialog(None, move |url, alt, title| {
                     let views = me.views.lock().unwrap();
                     if let Some(view) = views.get(me.current_view()) {
-                        view.image(Url::parse(&url.get_text().unwrap().trim())
-                                       .ok()
-                                       .expect("Failed to parse URL"));
+                        view.image(&url, &alt, &title);
                     }
-                    dialog2.destroy();
                 });
-                dialog.show_all();
-                dialog.run();
             });
         let me = self.clone();
         self.insert_link
             .connect_clicked(move |_| {
-                // Build from glade
-                let builder = Builder::new_from_string(url_dialog_src);
-                let url: Entry = builder.get_object("url").unwrap();
-                let dialog: Dialog = builder.get_object("dialog").unwrap();
                 let me = me.clone();
-                let ok: Button = builder.get_object("ok").unwrap();
-                let dialog2 = dialog.clone();
-                ok.connect_clicked(move |_| {
-
+                view::link_dialog(None, move |url, title, label| {
                     let views = me.views.lock().unwrap();
                     if let Some(view) = views.get(me.current_view()) {
-                        view.link(Url::parse(&url.get_text().unwrap().trim())
-                                      .ok()
-                                      .expect("Failed to parse URL"));
+                        view.link(&url, &title, label);
                     }
-                    dialog2.destroy();
                 });
-                dialog.show_all();
-                dialog.run();
             });
         self.window
             .connect_delete_event(|_, _| {
@@ -384,3 +506,43 @@ impl App {
                                   });
     }
 }
+
+/// Try loading or saving a document, asking for a login for as long as the server turns down
+/// the one given, and keeping the login if asked to once it works
+fn with_login<T, F>(view: &View, mut attempt: F) -> Result<T, Error>
+    where F: FnMut() -> Result<T, Error>
+{
+    let source = view.source.lock().unwrap().clone();
+    let text = format!("{}", source);
+    let site = match source {
+        Source::Url(ref url) => auth::site(url),
+        _ => None,
+    };
+    let mut result = attempt();
+    let mut remember = false;
+    let mut rejected = false;
+    while let Err(Error::Unauthorized(ref challenge)) = result {
+        let entered = view::login_dialog(site.as_ref().map_or(&text, |site| site),
+                                         challenge,
+                                         rejected);
+        match entered {
+            Some((credentials, keep)) => {
+                view.remote.lock().unwrap().credentials = Some(credentials);
+                remember = keep;
+                rejected = true;
+            }
+            None => break,
+        }
+        result = attempt();
+    }
+    // Only logins that worked are worth keeping
+    if let (true, true, Some(site)) = (result.is_ok(), remember, site) {
+        let credentials = view.remote.lock().unwrap().credentials.clone();
+        if let Some(credentials) = credentials {
+            if let Err(err) = auth::remember(&site, &credentials) {
+                view.show_error(&format!("Couldn't remember the login for {}: {}", site, err));
+            }
+        }
+    }
+    result
+}
@@ -0,0 +1,274 @@
+use base64;
+use percent_encoding::{percent_decode, utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use hyper::Url;
+use hyper::header::Headers;
+
+use util;
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// The name logins are kept under in the keyring
+const SERVICE: &str = "litepad";
+
+/// How to prove to a server who is asking
+#[derive(Clone, Eq, PartialEq)]
+pub enum Credentials {
+    /// A user name and password, sent with basic authentication
+    Basic { user: String, password: String },
+    /// A token, like an OAuth access token or an API key, sent as is
+    Bearer(String),
+    /// A client certificate and its key in a PKCS #12 file, given when connecting, along with
+    /// the password the file is encrypted with
+    Certificate { path: PathBuf, password: String },
+}
+
+impl Credentials {
+    /// Add the `Authorization` header for the credentials to a request, unless they are given
+    /// when connecting instead
+    pub fn authorize(&self, headers: &mut Headers) {
+        let value = match *self {
+            Credentials::Basic {
+                ref user,
+                ref password,
+            } => format!("Basic {}", base64::encode(format!("{}:{}", user, password).as_bytes())),
+            Credentials::Bearer(ref token) => format!("Bearer {}", token),
+            Credentials::Certificate { .. } => return,
+        };
+        headers.set_raw("Authorization", vec![value.into_bytes()]);
+    }
+    /// Write out as the kind of credentials followed by their fields, separated by spaces
+    fn to_line(&self) -> String {
+        let fields = match *self {
+            Credentials::Basic {
+                ref user,
+                ref password,
+            } => vec!["basic", user, password],
+            Credentials::Bearer(ref token) => vec!["bearer", token],
+            Credentials::Certificate {
+                ref path,
+                ref password,
+            } => vec!["certificate", path.to_str().unwrap_or_default(), password],
+        };
+        fields
+            .iter()
+            .map(|field| utf8_percent_encode(field, PATH_SEGMENT_ENCODE_SET).to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+    /// Read back credentials written by `to_line`
+    fn from_line(line: &str) -> Option<Credentials> {
+        // Fields can be empty, like a password that isn't set, so are split on every space
+        let fields: Vec<String> = line.trim_right_matches(|c| c == '\r' || c == '\n')
+            .split(' ')
+            .map(|field| percent_decode(field.as_bytes()).decode_utf8_lossy().into_owned())
+            .collect();
+        match fields.iter().map(|field| field.as_str()).collect::<Vec<_>>().as_slice() {
+            &["basic", user, password] => {
+                Some(Credentials::Basic {
+                         user: user.to_owned(),
+                         password: password.to_owned(),
+                     })
+            }
+            &["bearer", token] => Some(Credentials::Bearer(token.to_owned())),
+            &["certificate", path, password] => {
+                Some(Credentials::Certificate {
+                         path: PathBuf::from(path),
+                         password: password.to_owned(),
+                     })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Shows which kind of credentials they are, but never the secrets in them
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Credentials::Basic { ref user, .. } => write!(f, "Basic({:?})", user),
+            Credentials::Bearer(_) => f.write_str("Bearer"),
+            Credentials::Certificate { ref path, .. } => write!(f, "Certificate({:?})", path),
+        }
+    }
+}
+
+/// What a server asked for when it turned down a request, from its `WWW-Authenticate` header
+#[derive(Clone, Debug, Default)]
+pub struct Challenge {
+    /// Whether it wants a token rather than a user name and password
+    pub bearer: bool,
+    /// What the server calls the part of it that needs a login
+    pub realm: Option<String>,
+}
+
+impl Challenge {
+    pub fn parse(header: &str) -> Challenge {
+        let header = header.trim();
+        let scheme = header.split_whitespace().next().unwrap_or_default();
+        let realm = header
+            .find("realm=")
+            .map(|start| {
+                     let value = &header[start + "realm=".len()..];
+                     if value.starts_with('"') {
+                         value[1..].split('"').next().unwrap_or_default()
+                     } else {
+                         value
+                             .split(|c: char| c == ',' || c.is_whitespace())
+                             .next()
+                             .unwrap_or_default()
+                     }
+                 })
+            .filter(|realm| !realm.is_empty())
+            .map(str::to_owned);
+        Challenge {
+            bearer: scheme.eq_ignore_ascii_case("bearer"),
+            realm,
+        }
+    }
+}
+
+/// The host and port of a URL, which logins are kept for
+pub fn site(url: &Url) -> Option<String> {
+    url.host_str()
+        .map(|host| match url.port() {
+                 Some(port) => format!("{}:{}", host, port),
+                 None => host.to_owned(),
+             })
+}
+
+/// Look up the login kept for a site, in the keyring or failing that the credentials file
+///
+/// This can block on the keyring, so is best called off the main thread where possible.
+pub fn lookup(site: &str) -> Option<Credentials> {
+    Command::new("secret-tool")
+        .args(&["lookup", "service", SERVICE, "host", site])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| Credentials::from_line(&String::from_utf8_lossy(&output.stdout)))
+        .or_else(|| {
+            let file = util::config_file("credentials").and_then(|path| File::open(path).ok());
+            file.and_then(|file| {
+                BufReader::new(file)
+                    .lines()
+                    .filter_map(|line| line.ok())
+                    .filter_map(|line| {
+                        let mut parts = line.splitn(2, ' ');
+                        match (parts.next(), parts.next()) {
+                            (Some(host), Some(rest)) if host == site => {
+                                Credentials::from_line(rest)
+                            }
+                            _ => None,
+                        }
+                    })
+                    .next()
+            })
+        })
+}
+
+/// Keep the login for a site for next time, in the keyring if there is one, or else in the
+/// credentials file, which only its owner can read
+pub fn remember(site: &str, credentials: &Credentials) -> io::Result<()> {
+    let line = credentials.to_line();
+    let stored = Command::new("secret-tool")
+        .args(&["store", &format!("--label=Litepad login for {}", site), "service", SERVICE,
+                "host", site])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .and_then(|mut child| {
+                      if let Some(mut stdin) = child.stdin.take() {
+                          stdin.write_all(line.as_bytes())?;
+                      }
+                      child.wait()
+                  })
+        .map(|status| status.success())
+        .unwrap_or(false);
+    if stored {
+        return Ok(());
+    }
+    let path = util::config_file("credentials")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No home directory"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Logins for other sites are kept, and any old one for this site replaced
+    let mut lines: Vec<String> = match File::open(&path) {
+        Ok(file) => {
+            BufReader::new(file)
+                .lines()
+                .filter_map(|line| line.ok())
+                .filter(|line| line.split_whitespace().next() != Some(site))
+                .collect()
+        }
+        Err(_) => Vec::new(),
+    };
+    lines.push(format!("{} {}", site, line));
+    let mut file = private_file(&path)?;
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Open a file for writing that only its owner can read
+#[cfg(unix)]
+fn private_file(path: &PathBuf) -> io::Result<File> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // Files made before are tightened too
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn private_file(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_are_read_back_as_written() {
+        let logins = [Credentials::Basic {
+                          user: "me".to_owned(),
+                          password: "open sesame".to_owned(),
+                      },
+                      Credentials::Basic {
+                          user: "first last".to_owned(),
+                          password: String::new(),
+                      },
+                      Credentials::Bearer("a b%20c\nd".to_owned()),
+                      Credentials::Certificate {
+                          path: PathBuf::from("/home/me/my keys/ключ.p12"),
+                          password: " 100% ".to_owned(),
+                      }];
+        for login in &logins {
+            let line = login.to_line();
+            assert!(!line.contains('\n'));
+            assert!(Credentials::from_line(&format!("{}\n", line)) == Some(login.clone()),
+                    "{} wasn't read back",
+                    line);
+        }
+        assert!(Credentials::from_line("basic me").is_none());
+        assert!(Credentials::from_line("other x").is_none());
+    }
+}
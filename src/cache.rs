@@ -0,0 +1,361 @@
+use auth::Credentials;
+use network::Settings;
+use util;
+
+use hyper::Url;
+use hyper::header::{CacheControl, CacheDirective, Headers, LastModified};
+use hyper::method::Method;
+use hyper::status::StatusCode;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The most the cache can take up on disk before the oldest entries are evicted
+const MAX_SIZE: u64 = 100 * 1024 * 1024;
+
+/// What is known about a cached response, kept next to its body
+#[derive(Default)]
+struct Entry {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// When the response stops being fresh, in seconds since the epoch
+    expires: u64,
+}
+
+impl Entry {
+    fn read(path: &PathBuf) -> io::Result<Entry> {
+        let mut entry = Entry::default();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some("etag"), Some(etag)) => entry.etag = Some(etag.to_owned()),
+                (Some("last-modified"), Some(date)) => entry.last_modified = Some(date.to_owned()),
+                (Some("expires"), Some(expires)) => entry.expires = expires.parse().unwrap_or(0),
+                _ => (),
+            }
+        }
+        Ok(entry)
+    }
+    fn write(&self, path: &PathBuf) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        if let Some(ref etag) = self.etag {
+            writeln!(file, "etag {}", etag)?;
+        }
+        if let Some(ref date) = self.last_modified {
+            writeln!(file, "last-modified {}", date)?;
+        }
+        writeln!(file, "expires {}", self.expires)
+    }
+    /// Work out the validators and freshness of a response from its headers
+    fn from_headers(headers: &Headers) -> Entry {
+        let raw = |name| {
+            headers
+                .get_raw(name)
+                .and_then(|values| values.first())
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+        };
+        let now = now();
+        let max_age = headers
+            .get::<CacheControl>()
+            .and_then(|directives| {
+                directives
+                    .iter()
+                    .filter_map(|directive| match *directive {
+                                    CacheDirective::NoCache => Some(0),
+                                    CacheDirective::MaxAge(age) => Some(age as u64),
+                                    _ => None,
+                                })
+                    .min()
+            });
+        // Without being told, responses stay fresh for a tenth of how long ago they last changed
+        let heuristic = || {
+            headers
+                .get::<LastModified>()
+                .map(|modified| (modified.0).0.to_timespec().sec as u64)
+                .map_or(0, |modified| now.saturating_sub(modified) / 10)
+        };
+        Entry {
+            etag: raw("ETag"),
+            last_modified: raw("Last-Modified"),
+            expires: now + max_age.unwrap_or_else(heuristic),
+        }
+    }
+}
+
+/// The current time in seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// Find the directory the cache is kept in, under the XDG cache directory
+fn directory() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::home_dir().map(|home| home.join(".cache")))
+        .map(|cache| cache.join("litepad").join("images"))
+}
+
+/// Hash a URL into the name of its cache entry, with FNV-1a so that names are stable
+fn key(url: &Url) -> String {
+    let hash = url.as_str()
+        .bytes()
+        .fold(0xcbf29ce484222325u64,
+              |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    format!("{:016x}", hash)
+}
+
+/// Fetch the contents of a URL over HTTP, from the cache while it is fresh, revalidating it
+/// once it isn't, and falling back on it when offline
+///
+/// Responses to requests sent with a login aren't cached, as they may be for that login alone.
+pub fn fetch(url: &Url, credentials: Option<&Credentials>) -> Result<Vec<u8>, String> {
+    fetch_in(directory(), &Settings::load(), url, credentials)
+}
+
+/// Fetch the contents of a URL like `fetch`, with the cache kept in `dir` and the network
+/// settings given
+fn fetch_in(dir: Option<PathBuf>,
+            settings: &Settings,
+            url: &Url,
+            credentials: Option<&Credentials>)
+            -> Result<Vec<u8>, String> {
+    let paths = dir.filter(|_| credentials.is_none())
+        .map(|dir| {
+                 let key = key(url);
+                 (dir.join(&key), dir.join(key + ".meta"))
+             });
+    let cached = paths
+        .as_ref()
+        .and_then(|&(ref body, ref meta)| {
+            let mut bytes = Vec::new();
+            File::open(body)
+                .and_then(|mut file| file.read_to_end(&mut bytes))
+                .and_then(|_| Entry::read(meta))
+                .ok()
+                .map(|entry| (entry, bytes))
+        });
+    let mut headers = Headers::new();
+    if let Some((ref entry, ref bytes)) = cached {
+        if entry.expires > now() {
+            return Ok(bytes.clone());
+        }
+        if let Some(ref etag) = entry.etag {
+            headers.set_raw("If-None-Match", vec![etag.clone().into_bytes()]);
+        }
+        if let Some(ref date) = entry.last_modified {
+            headers.set_raw("If-Modified-Since", vec![date.clone().into_bytes()]);
+        }
+    }
+    let sent = util::send_with(settings, Method::Get, url, &headers, None, credentials);
+    let mut res = match sent {
+        Ok(res) => res,
+        Err(err) => {
+            return match cached {
+                       Some((_, bytes)) => Ok(bytes),
+                       None => Err(err.to_string()),
+                   }
+        }
+    };
+    let no_store = res.headers
+        .get::<CacheControl>()
+        .map_or(false, |directives| directives.contains(&CacheDirective::NoStore));
+    let (entry, bytes) = match (res.status, cached) {
+        (StatusCode::NotModified, Some((old, bytes))) => {
+            // Validators left out of the response carry on from before
+            let mut entry = Entry::from_headers(&res.headers);
+            entry.etag = entry.etag.or(old.etag);
+            entry.last_modified = entry.last_modified.or(old.last_modified);
+            (entry, bytes)
+        }
+        (status, _) if status.is_success() => {
+            let mut bytes = Vec::new();
+            res.read_to_end(&mut bytes).map_err(|err| err.to_string())?;
+            (Entry::from_headers(&res.headers), bytes)
+        }
+        (status, _) => return Err(status.to_string()),
+    };
+    if let Some((body, meta)) = paths {
+        if no_store {
+            fs::remove_file(&body).ok();
+            fs::remove_file(&meta).ok();
+        } else {
+            // The cache is only an optimisation, so failing to write to it isn't an error
+            store(&body, &meta, &bytes, &entry).ok();
+        }
+    }
+    Ok(bytes)
+}
+
+/// Write an entry to the cache, then evict the oldest entries while it is too big
+fn store(body: &PathBuf, meta: &PathBuf, bytes: &[u8], entry: &Entry) -> io::Result<()> {
+    if let Some(dir) = body.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    File::create(body)?.write_all(bytes)?;
+    entry.write(meta)?;
+    evict(body.parent().unwrap_or(body), MAX_SIZE)
+}
+
+/// Evict the oldest entries in the cache in `dir` while it takes up more than `max_size`
+fn evict(dir: &Path, max_size: u64) -> io::Result<()> {
+    let mut bodies = Vec::new();
+    for file in fs::read_dir(dir)? {
+        let file = file?;
+        let metadata = file.metadata()?;
+        if file.path().extension().is_none() {
+            bodies.push((metadata.modified()?, metadata.len(), file.path()));
+        }
+    }
+    bodies.sort();
+    let mut size: u64 = bodies.iter().map(|&(_, len, _)| len).sum();
+    for (_, len, path) in bodies {
+        if size <= max_size {
+            break;
+        }
+        fs::remove_file(&path)?;
+        fs::remove_file(path.with_extension("meta")).ok();
+        size -= len;
+    }
+    Ok(())
+}
+
+/// Remove everything in the cache
+pub fn clear() -> io::Result<()> {
+    clear_in(directory())
+}
+
+/// Remove everything in the cache kept in `dir`
+fn clear_in(dir: Option<PathBuf>) -> io::Result<()> {
+    match dir {
+        Some(ref dir) if dir.exists() => fs::remove_dir_all(dir),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server;
+
+    use std::thread;
+    use std::time::Duration;
+
+    /// Make an empty directory for a test to keep files in
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("litepad-cache-{}", name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn entries_are_read_back_as_written() {
+        let path = test_dir("entries").join("entry.meta");
+        let entry = Entry {
+            etag: Some("\"v1\"".to_owned()),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_owned()),
+            expires: 1234,
+        };
+        entry.write(&path).unwrap();
+        let read = Entry::read(&path).unwrap();
+        assert_eq!(read.etag, entry.etag);
+        assert_eq!(read.last_modified, entry.last_modified);
+        assert_eq!(read.expires, 1234);
+    }
+
+    #[test]
+    fn freshness_comes_from_the_headers() {
+        let mut headers = Headers::new();
+        headers.set(CacheControl(vec![CacheDirective::MaxAge(60)]));
+        let expires = Entry::from_headers(&headers).expires;
+        assert!(expires >= now() + 59 && expires <= now() + 60);
+        headers.set(CacheControl(vec![CacheDirective::MaxAge(60), CacheDirective::NoCache]));
+        assert!(Entry::from_headers(&headers).expires <= now());
+        // Responses are fresh for a tenth of the time since they changed
+        let mut headers = Headers::new();
+        headers.set_raw("Last-Modified", vec![b"Thu, 01 Jan 1970 00:16:40 GMT".to_vec()]);
+        let expires = Entry::from_headers(&headers).expires;
+        let fresh_for = (now() - 1000) / 10;
+        assert!(expires >= now() + fresh_for - 1 && expires <= now() + fresh_for + 1);
+    }
+
+    #[test]
+    fn oldest_entries_are_evicted() {
+        let dir = test_dir("evict");
+        for name in &["a", "b", "c"] {
+            File::create(dir.join(name)).unwrap().write_all(&[0; 10]).unwrap();
+            File::create(dir.join(name).with_extension("meta")).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        evict(&dir, 20).unwrap();
+        assert!(!dir.join("a").exists() && !dir.join("a.meta").exists());
+        assert!(dir.join("b").exists() && dir.join("c.meta").exists());
+    }
+
+    #[test]
+    fn responses_are_revalidated_once_stale() {
+        let dir = test_dir("revalidate");
+        let settings = Settings::default();
+        let (url, requests) = test_server::serve(&["HTTP/1.1 200 OK\r\nETag: \"v1\"\r\n\
+                                                    Cache-Control: max-age=0\r\n\
+                                                    Content-Length: 3\r\n\r\none",
+                                                   "HTTP/1.1 304 Not Modified\r\n\
+                                                    Cache-Control: max-age=60\r\n\r\n"]);
+        assert_eq!(fetch_in(Some(dir.clone()), &settings, &url, None).unwrap(), b"one");
+        assert!(!requests.recv().unwrap().contains("If-None-Match"));
+        assert_eq!(fetch_in(Some(dir.clone()), &settings, &url, None).unwrap(), b"one");
+        assert!(requests.recv().unwrap().contains("If-None-Match: \"v1\""));
+        // Now it is fresh, it isn't asked for again
+        assert_eq!(fetch_in(Some(dir), &settings, &url, None).unwrap(), b"one");
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn responses_not_to_be_stored_are_not() {
+        let dir = test_dir("no-store");
+        let settings = Settings::default();
+        let (url, _requests) = test_server::serve(&["HTTP/1.1 200 OK\r\n\
+                                                     Cache-Control: no-store\r\n\
+                                                     Content-Length: 6\r\n\r\nsecret"]);
+        assert_eq!(fetch_in(Some(dir.clone()), &settings, &url, None).unwrap(), b"secret");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn responses_for_a_login_are_not_stored() {
+        let dir = test_dir("login");
+        let (url, requests) = test_server::serve(&["HTTP/1.1 200 OK\r\n\
+                                                    Cache-Control: max-age=60\r\n\
+                                                    Content-Length: 6\r\n\r\nsecret",
+                                                   "HTTP/1.1 401 Unauthorized\r\n\
+                                                    Content-Length: 0\r\n\r\n"]);
+        let login = Credentials::Bearer("token".to_owned());
+        let settings = Settings::default();
+        assert_eq!(fetch_in(Some(dir.clone()), &settings, &url, Some(&login)).unwrap(),
+                   b"secret");
+        assert!(requests.recv().unwrap().contains("Authorization: Bearer token\r\n"));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        // Without the login, the server is asked again
+        assert!(fetch_in(Some(dir.clone()), &settings, &url, None).is_err());
+        assert!(!requests.recv().unwrap().contains("Authorization"));
+    }
+
+    #[test]
+    fn clearing_removes_everything() {
+        let dir = test_dir("clear").join("images");
+        let (url, _requests) = test_server::serve(&["HTTP/1.1 200 OK\r\n\
+                                                     Content-Length: 3\r\n\r\none"]);
+        fetch_in(Some(dir.clone()), &Settings::default(), &url, None).unwrap();
+        assert!(dir.exists());
+        clear_in(Some(dir.clone())).unwrap();
+        assert!(!dir.exists());
+    }
+}
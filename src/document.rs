@@ -0,0 +1,2082 @@
+use pulldown_cmark::{self, CodeBlockKind, Event, LinkType, Options, Parser, Tag};
+
+use std::cmp::max;
+use std::collections::HashMap;
+use std::iter::repeat;
+
+/// A span of text-level content
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Inline {
+    Text(String),
+    Code(String),
+    Html(String),
+    Emphasis(Vec<Inline>),
+    Strong(Vec<Inline>),
+    Strikethrough(Vec<Inline>),
+    /// The checkbox starting the item of a task list, and whether it is checked
+    TaskMarker(bool),
+    /// A reference to the footnote with the label given
+    FootnoteReference(String),
+    Link {
+        url: String,
+        title: String,
+        content: Vec<Inline>,
+        reference: Option<Reference>,
+    },
+    Image {
+        url: String,
+        title: String,
+        alt: String,
+        reference: Option<Reference>,
+    },
+    SoftBreak,
+    HardBreak,
+}
+
+/// How a link or image written as a reference names its definition
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Reference {
+    /// `[text][label]`
+    Full(String),
+    /// `[label][]`
+    Collapsed(String),
+    /// `[label]`
+    Shortcut(String),
+}
+
+impl Reference {
+    pub fn label(&self) -> &str {
+        match *self {
+            Reference::Full(ref label) |
+            Reference::Collapsed(ref label) |
+            Reference::Shortcut(ref label) => label,
+        }
+    }
+}
+
+/// A link reference definition, `[label]: url "title"`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Definition {
+    pub label: String,
+    pub url: String,
+    pub title: String,
+}
+
+/// A block-level element
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Block {
+    Paragraph(Vec<Inline>),
+    Header(i32, Vec<Inline>),
+    CodeBlock { info: String, text: String },
+    Html(String),
+    Rule,
+    BlockQuote(Vec<Block>),
+    List {
+        start: Option<usize>,
+        tight: bool,
+        items: Vec<Vec<Block>>,
+    },
+    FootnoteDefinition { label: String, blocks: Vec<Block> },
+    /// A table, whose first row is its head
+    Table {
+        alignments: Vec<Alignment>,
+        rows: Vec<Vec<Vec<Inline>>>,
+    },
+}
+
+/// How the cells of a table column are aligned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alignment {
+    None,
+    Left,
+    Center,
+    Right,
+}
+
+impl<'a> From<&'a pulldown_cmark::Alignment> for Alignment {
+    fn from(alignment: &pulldown_cmark::Alignment) -> Alignment {
+        match *alignment {
+            pulldown_cmark::Alignment::None => Alignment::None,
+            pulldown_cmark::Alignment::Left => Alignment::Left,
+            pulldown_cmark::Alignment::Center => Alignment::Center,
+            pulldown_cmark::Alignment::Right => Alignment::Right,
+        }
+    }
+}
+
+/// A Markdown document, independent of how it is displayed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Document {
+    pub blocks: Vec<Block>,
+    /// The link reference definitions, which are written after the blocks
+    pub definitions: Vec<Definition>,
+}
+
+impl Document {
+    /// Parse a document from Markdown text
+    pub fn parse(text: &str) -> Document {
+        let options = options();
+        let mut events = Parser::new_ext(text, options);
+        let mut blocks = parse_blocks(&mut events).0;
+        split_definitions(&mut blocks);
+        // The parser doesn't give the labels of references, so they are found separately
+        let mut references = references(text, options).into_iter();
+        visit_inlines(&mut blocks, &mut |inline| match *inline {
+            Inline::Link { reference: Some(ref mut reference), .. } |
+            Inline::Image { reference: Some(ref mut reference), .. } => {
+                if let Some(found) = references.next() {
+                    *reference = found;
+                }
+            }
+            _ => (),
+        });
+        Document {
+            blocks,
+            definitions: definitions(text, options),
+        }
+    }
+    /// Find the definition with the label given
+    pub fn definition(&self, label: &str) -> Option<&Definition> {
+        let label = normalize_label(label);
+        self.definitions
+            .iter()
+            .find(|definition| normalize_label(&definition.label) == label)
+    }
+    /// Serialize the document back to Markdown text
+    pub fn to_markdown(&self) -> String {
+        // References to definitions that are missing or no longer match are written inline
+        let mut blocks = self.blocks.clone();
+        visit_inlines(&mut blocks, &mut |inline| match *inline {
+            Inline::Link {
+                ref url,
+                ref title,
+                ref mut reference,
+                ..
+            } |
+            Inline::Image {
+                ref url,
+                ref title,
+                ref mut reference,
+                ..
+            } => {
+                let defined = reference
+                    .as_ref()
+                    .and_then(|reference| self.definition(reference.label()))
+                    .map_or(false, |definition| {
+                        definition.url == *url && definition.title == *title
+                    });
+                if !defined {
+                    *reference = None;
+                }
+            }
+            _ => (),
+        });
+        let mut text = blocks_to_markdown(&blocks, false);
+        if !self.definitions.is_empty() {
+            if !text.is_empty() {
+                text.push_str("\n\n");
+            }
+            let definitions: Vec<String> = self.definitions
+                .iter()
+                .map(|definition| {
+                         format!("[{}]: {}",
+                                 definition.label,
+                                 link_target(&definition.url, &definition.title))
+                     })
+                .collect();
+            text.push_str(&definitions.join("\n"));
+        }
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text
+    }
+    /// Move footnote definitions to the end of the document in the order they are first
+    /// referenced, renumbering numeric labels to match and returning the labels changed
+    pub fn collect_footnotes(&mut self) -> HashMap<String, String> {
+        let mut definitions = Vec::new();
+        take_definitions(&mut self.blocks, &mut definitions);
+        let mut order = Vec::new();
+        footnote_labels(&self.blocks, &mut order);
+        for &(ref label, ref blocks) in &definitions {
+            footnote_labels(blocks, &mut order);
+            if !order.contains(label) {
+                order.push(label.clone());
+            }
+        }
+        let renamed: HashMap<String, String> = order
+            .iter()
+            .filter(|label| is_number(label))
+            .enumerate()
+            .map(|(i, label)| (label.clone(), (i + 1).to_string()))
+            .collect();
+        rename_footnotes(&mut self.blocks, &renamed);
+        definitions.sort_by_key(|&(ref label, _)| order.iter().position(|other| other == label));
+        for (label, mut blocks) in definitions {
+            rename_footnotes(&mut blocks, &renamed);
+            self.blocks.push(Block::FootnoteDefinition {
+                                 label: renamed.get(&label).cloned().unwrap_or(label),
+                                 blocks,
+                             });
+        }
+        renamed
+    }
+    /// Find the lowest number not yet used as a footnote label
+    pub fn next_footnote_label(&self) -> String {
+        let mut labels = Vec::new();
+        footnote_labels(&self.blocks, &mut labels);
+        let next = (1..)
+            .find(|number: &usize| !labels.contains(&number.to_string()))
+            .unwrap();
+        next.to_string()
+    }
+}
+
+/// The text a document was loaded from, kept so unedited documents save byte-for-byte
+#[derive(Clone, Debug)]
+pub struct Original {
+    pub text: String,
+    /// The document as read back from wherever it is shown straight after loading
+    pub document: Document,
+}
+
+impl Original {
+    /// Get the text to save a document as if it can be saved as it was loaded, which it can
+    /// when it hasn't been edited or the edits leave it as it was
+    pub fn unedited(&self, document: &Document, modified: bool) -> Option<&str> {
+        if !modified || *document == self.document {
+            Some(&self.text)
+        } else {
+            None
+        }
+    }
+}
+
+/// Formatting applied to a run of text in a flat representation, such as a `TextBuffer`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Style {
+    Link(String, String, Option<Reference>),
+    Strong,
+    Emphasis,
+    Strikethrough,
+    Code,
+}
+
+/// Builds nested inlines out of flat runs of styled content
+#[derive(Default)]
+pub struct InlineBuilder {
+    root: Vec<Inline>,
+    open: Vec<(Style, Vec<Inline>)>,
+}
+
+impl InlineBuilder {
+    pub fn new() -> InlineBuilder {
+        InlineBuilder::default()
+    }
+    pub fn push_text(&mut self, styles: &[Style], text: &str) {
+        self.push(styles, Inline::Text(text.to_owned()));
+    }
+    /// Add an inline with the styles given, from outermost to innermost
+    pub fn push(&mut self, styles: &[Style], inline: Inline) {
+        let common = self.open
+            .iter()
+            .zip(styles)
+            .take_while(|&(&(ref open, _), style)| open == style)
+            .count();
+        while self.open.len() > common {
+            self.close();
+        }
+        for style in &styles[common..] {
+            self.open.push((style.clone(), Vec::new()));
+        }
+        push_inline(self.children(), inline);
+    }
+    pub fn finish(mut self) -> Vec<Inline> {
+        while !self.open.is_empty() {
+            self.close();
+        }
+        self.root
+    }
+    fn children(&mut self) -> &mut Vec<Inline> {
+        match self.open.last_mut() {
+            Some(&mut (_, ref mut children)) => children,
+            None => &mut self.root,
+        }
+    }
+    fn close(&mut self) {
+        let (style, children) = self.open.pop().unwrap();
+        let inline = match style {
+            Style::Link(url, title, reference) => {
+                Inline::Link {
+                    url,
+                    title,
+                    content: children,
+                    reference,
+                }
+            }
+            Style::Strong => Inline::Strong(children),
+            Style::Emphasis => Inline::Emphasis(children),
+            Style::Strikethrough => Inline::Strikethrough(children),
+            Style::Code => Inline::Code(plain_text(&children)),
+        };
+        push_inline(self.children(), inline);
+    }
+}
+
+/// A container of blocks in a flat representation, such as a `TextBuffer`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Container {
+    /// A list, told apart from adjacent lists by its `id`
+    List {
+        id: usize,
+        start: Option<usize>,
+        tight: bool,
+    },
+    /// A block quote, told apart from adjacent quotes by its `id`
+    BlockQuote { id: usize },
+    /// The definition of a footnote, told apart from others by its `id`
+    FootnoteDefinition { id: usize, label: String },
+}
+
+/// Builds nested blocks out of blocks laid out flat
+#[derive(Default)]
+pub struct BlockBuilder {
+    root: Vec<Block>,
+    open: Vec<(Container, Vec<Vec<Block>>)>,
+}
+
+impl BlockBuilder {
+    pub fn new() -> BlockBuilder {
+        BlockBuilder::default()
+    }
+    /// Start a new item of the innermost of the containers given, from outermost to innermost
+    pub fn item(&mut self, containers: &[Container]) {
+        self.enter(containers);
+        if let Some(&mut (_, ref mut items)) = self.open.last_mut() {
+            items.push(Vec::new());
+        }
+    }
+    /// Add a block inside the containers given, from outermost to innermost
+    pub fn push(&mut self, containers: &[Container], block: Block) {
+        self.enter(containers);
+        self.children().push(block);
+    }
+    pub fn finish(mut self) -> Vec<Block> {
+        while !self.open.is_empty() {
+            self.close();
+        }
+        self.root
+    }
+    /// Close any open containers not given and open the ones given that aren't yet
+    fn enter(&mut self, containers: &[Container]) {
+        let common = self.open
+            .iter()
+            .zip(containers)
+            .take_while(|&(&(ref open, _), container)| open == container)
+            .count();
+        while self.open.len() > common {
+            self.close();
+        }
+        for container in &containers[common..] {
+            self.open.push((container.clone(), Vec::new()));
+        }
+    }
+    fn children(&mut self) -> &mut Vec<Block> {
+        match self.open.last_mut() {
+            Some(&mut (_, ref mut items)) => {
+                if items.is_empty() {
+                    items.push(Vec::new());
+                }
+                items.last_mut().unwrap()
+            }
+            None => &mut self.root,
+        }
+    }
+    fn close(&mut self) {
+        let (container, items) = self.open.pop().unwrap();
+        let block = match container {
+            Container::List { start, tight, .. } => Block::List { start, tight, items },
+            Container::BlockQuote { .. } => Block::BlockQuote(items.concat()),
+            Container::FootnoteDefinition { label, .. } => {
+                Block::FootnoteDefinition {
+                    label,
+                    blocks: items.concat(),
+                }
+            }
+        };
+        self.children().push(block);
+    }
+}
+
+/// The extensions to CommonMark that documents are parsed with
+fn options() -> Options {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+    options
+}
+
+/// Get the text of some inlines with all formatting stripped
+pub fn plain_text(inlines: &[Inline]) -> String {
+    let mut text = String::new();
+    for inline in inlines {
+        match *inline {
+            Inline::Text(ref t) |
+            Inline::Code(ref t) |
+            Inline::Html(ref t) => text.push_str(t),
+            Inline::Emphasis(ref content) |
+            Inline::Strong(ref content) |
+            Inline::Strikethrough(ref content) |
+            Inline::Link { ref content, .. } => text.push_str(&plain_text(content)),
+            Inline::Image { ref alt, .. } => text.push_str(alt),
+            Inline::TaskMarker(_) |
+            Inline::FootnoteReference(_) => (),
+            Inline::SoftBreak | Inline::HardBreak => text.push('\n'),
+        }
+    }
+    text
+}
+
+/// Add an inline, merging adjacent text
+fn push_inline(inlines: &mut Vec<Inline>, inline: Inline) {
+    if let Inline::Text(ref text) = inline {
+        if let Some(&mut Inline::Text(ref mut last)) = inlines.last_mut() {
+            last.push_str(text);
+            return;
+        }
+    }
+    inlines.push(inline);
+}
+
+/// Skip events until the end of the tag just started
+fn skip<'a, I: Iterator<Item = Event<'a>>>(events: &mut I) {
+    let mut depth = 0;
+    for event in events {
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) if depth == 0 => break,
+            Event::End(_) => depth -= 1,
+            _ => (),
+        }
+    }
+}
+
+/// Collect the text until the end of the tag just started
+fn parse_text<'a, I: Iterator<Item = Event<'a>>>(events: &mut I) -> String {
+    let mut text = String::new();
+    let mut depth = 0;
+    for event in events {
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) if depth == 0 => break,
+            Event::End(_) => depth -= 1,
+            Event::Text(t) |
+            Event::Code(t) |
+            Event::Html(t) => text.push_str(&t),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            _ => (),
+        }
+    }
+    text
+}
+
+/// Parse blocks until the end of the enclosing container, returning them
+/// and whether any paragraph was started, which only happens in loose list items
+fn parse_blocks<'a, I: Iterator<Item = Event<'a>>>(events: &mut I) -> (Vec<Block>, bool) {
+    let mut blocks = Vec::new();
+    let mut bare = Vec::new();
+    let mut found_paragraph = false;
+    while let Some(event) = events.next() {
+        let block = match event {
+            Event::Start(Tag::Paragraph) => {
+                found_paragraph = true;
+                let mut inlines = parse_inlines(events);
+                // In loose lists the marker of a task item comes before its first paragraph
+                if bare.len() == 1 &&
+                   match bare[0] {
+                       Inline::TaskMarker(_) => true,
+                       _ => false,
+                   } {
+                    inlines.insert(0, bare.pop().unwrap());
+                }
+                Block::Paragraph(autolink(inlines))
+            }
+            Event::Start(Tag::Heading(level)) => {
+                Block::Header(level as i32, autolink(parse_inlines(events)))
+            }
+            Event::Start(Tag::CodeBlock(kind)) => {
+                Block::CodeBlock {
+                    info: match kind {
+                        CodeBlockKind::Fenced(info) => info.into_string(),
+                        CodeBlockKind::Indented => String::new(),
+                    },
+                    text: parse_text(events),
+                }
+            }
+            Event::Rule => Block::Rule,
+            Event::Start(Tag::BlockQuote) => Block::BlockQuote(parse_blocks(events).0),
+            Event::Start(Tag::List(start)) => {
+                parse_list(start.map(|start| start as usize), events)
+            }
+            Event::Start(Tag::Table(alignments)) => {
+                Block::Table {
+                    alignments: alignments.iter().map(Alignment::from).collect(),
+                    rows: parse_rows(events),
+                }
+            }
+            Event::TaskListMarker(checked) => {
+                bare.push(Inline::TaskMarker(checked));
+                continue;
+            }
+            // Inline HTML can also be found outside of a paragraph, in tight list items
+            Event::Html(ref html) if !html.ends_with('\n') => {
+                bare.push(Inline::Html(html.to_string()));
+                continue;
+            }
+            Event::Html(html) => {
+                // An HTML block can be split across several events
+                if bare.is_empty() {
+                    if let Some(&mut Block::Html(ref mut last)) = blocks.last_mut() {
+                        last.push_str(&html);
+                        continue;
+                    }
+                }
+                Block::Html(html.into_string())
+            }
+            Event::Start(Tag::FootnoteDefinition(label)) => {
+                Block::FootnoteDefinition {
+                    label: label.into_string(),
+                    blocks: parse_blocks(events).0,
+                }
+            }
+            Event::Start(Tag::Item) |
+            Event::Start(Tag::TableHead) |
+            Event::Start(Tag::TableRow) |
+            Event::Start(Tag::TableCell) => {
+                skip(events);
+                continue;
+            }
+            Event::End(_) => break,
+            event => {
+                parse_inline(event, events, &mut bare);
+                continue;
+            }
+        };
+        if !bare.is_empty() {
+            blocks.push(Block::Paragraph(autolink(bare.split_off(0))));
+        }
+        blocks.push(block);
+    }
+    if !bare.is_empty() {
+        blocks.push(Block::Paragraph(autolink(bare)));
+    }
+    (blocks, found_paragraph)
+}
+
+fn parse_list<'a, I: Iterator<Item = Event<'a>>>(start: Option<usize>, events: &mut I) -> Block {
+    let mut items = Vec::new();
+    let mut tight = true;
+    while let Some(event) = events.next() {
+        match event {
+            Event::Start(Tag::Item) => {
+                let (blocks, loose) = parse_blocks(events);
+                if loose {
+                    tight = false;
+                }
+                items.push(blocks);
+            }
+            Event::Start(_) => skip(events),
+            Event::End(_) => break,
+            _ => (),
+        }
+    }
+    Block::List { start, tight, items }
+}
+
+/// Parse the rows of a table, head first, until the end of the table
+fn parse_rows<'a, I: Iterator<Item = Event<'a>>>(events: &mut I) -> Vec<Vec<Vec<Inline>>> {
+    let mut rows = Vec::new();
+    while let Some(event) = events.next() {
+        match event {
+            Event::Start(Tag::TableHead) |
+            Event::Start(Tag::TableRow) => {
+                let mut cells = Vec::new();
+                while let Some(event) = events.next() {
+                    match event {
+                        Event::Start(Tag::TableCell) => {
+                            cells.push(autolink(trim_inlines(parse_inlines(events))))
+                        }
+                        Event::Start(_) => skip(events),
+                        Event::End(_) => break,
+                        _ => (),
+                    }
+                }
+                rows.push(cells);
+            }
+            Event::Start(_) => skip(events),
+            Event::End(_) => break,
+            _ => (),
+        }
+    }
+    rows
+}
+
+/// Strip the whitespace padding a table cell from its edges
+fn trim_inlines(mut inlines: Vec<Inline>) -> Vec<Inline> {
+    if let Some(&mut Inline::Text(ref mut text)) = inlines.first_mut() {
+        *text = text.trim_left().to_owned();
+    }
+    if let Some(&mut Inline::Text(ref mut text)) = inlines.last_mut() {
+        *text = text.trim_right().to_owned();
+    }
+    inlines.retain(|inline| *inline != Inline::Text(String::new()));
+    inlines
+}
+
+/// Parse inlines until the end of the enclosing tag
+fn parse_inlines<'a, I: Iterator<Item = Event<'a>>>(events: &mut I) -> Vec<Inline> {
+    let mut inlines = Vec::new();
+    while let Some(event) = events.next() {
+        if let Event::End(_) = event {
+            break;
+        }
+        parse_inline(event, events, &mut inlines);
+    }
+    inlines
+}
+
+fn parse_inline<'a, I: Iterator<Item = Event<'a>>>(event: Event<'a>,
+                                                   events: &mut I,
+                                                   inlines: &mut Vec<Inline>) {
+    let inline = match event {
+        Event::Text(text) => Inline::Text(text.into_string()),
+        Event::Code(code) => Inline::Code(code.into_string()),
+        Event::Html(html) => Inline::Html(html.into_string()),
+        Event::SoftBreak => Inline::SoftBreak,
+        Event::HardBreak => Inline::HardBreak,
+        Event::TaskListMarker(checked) => Inline::TaskMarker(checked),
+        Event::FootnoteReference(label) => Inline::FootnoteReference(label.into_string()),
+        Event::Start(Tag::Emphasis) => Inline::Emphasis(parse_inlines(events)),
+        Event::Start(Tag::Strong) => Inline::Strong(parse_inlines(events)),
+        Event::Start(Tag::Strikethrough) => Inline::Strikethrough(parse_inlines(events)),
+        Event::Start(Tag::Link(kind, url, title)) => {
+            Inline::Link {
+                url: url.into_string(),
+                title: title.into_string(),
+                content: parse_inlines(events),
+                reference: reference(kind, "[]"),
+            }
+        }
+        Event::Start(Tag::Image(kind, url, title)) => {
+            Inline::Image {
+                url: url.into_string(),
+                title: title.into_string(),
+                alt: plain_text(&parse_inlines(events)),
+                reference: reference(kind, "[]"),
+            }
+        }
+        Event::Start(_) => {
+            skip(events);
+            return;
+        }
+        Event::End(_) |
+        Event::Rule => return,
+    };
+    push_inline(inlines, inline);
+}
+
+/// Get how a link or image of the kind given, written as `source`, names its definition
+fn reference(kind: LinkType, source: &str) -> Option<Reference> {
+    let source = source.trim_left_matches('!');
+    let source = source.get(..source.len().saturating_sub(1)).unwrap_or("");
+    let label = |start: usize| source.get(start + 1..).unwrap_or("").to_owned();
+    match kind {
+        LinkType::Reference => {
+            // Labels can only contain escaped brackets
+            let mut start = None;
+            let mut escaped = false;
+            for (i, c) in source.char_indices() {
+                if c == '[' && !escaped {
+                    start = Some(i);
+                }
+                escaped = c == '\\' && !escaped;
+            }
+            start.map(|start| Reference::Full(label(start)))
+        }
+        LinkType::Collapsed => {
+            Some(Reference::Collapsed(label(0).trim_right_matches("][").to_owned()))
+        }
+        LinkType::Shortcut => Some(Reference::Shortcut(label(0))),
+        _ => None,
+    }
+}
+
+/// Find how each link or image written as a reference names its definition, in order
+fn references(text: &str, options: Options) -> Vec<Reference> {
+    let mut references = Vec::new();
+    // Links in the alt text of images are flattened into text
+    let mut images = 0;
+    for (event, range) in Parser::new_ext(text, options).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Link(kind, ..)) |
+            Event::Start(Tag::Image(kind, ..)) => {
+                if images == 0 {
+                    references.extend(reference(kind, &text[range]));
+                }
+                if let Event::Start(Tag::Image(..)) = event {
+                    images += 1;
+                }
+            }
+            Event::End(Tag::Image(..)) => images -= 1,
+            _ => (),
+        }
+    }
+    references
+}
+
+/// Find the link reference definitions in some Markdown text
+fn definitions(text: &str, options: Options) -> Vec<Definition> {
+    // Lines that look like definitions are checked by the parser resolving references to them
+    let mut labels: Vec<&str> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim_left_matches(|c| c == ' ' || c == '>');
+        if line.starts_with('[') && !line.starts_with("[^") {
+            if let Some(end) = line.find("]:") {
+                let label = &line[1..end];
+                if !labels.iter().any(|other| normalize_label(other) == normalize_label(label)) {
+                    labels.push(label);
+                }
+            }
+        }
+    }
+    let mut probe = text.to_owned();
+    for label in &labels {
+        probe.push_str("\n\n[");
+        probe.push_str(label);
+        probe.push(']');
+    }
+    Parser::new_ext(&probe, options)
+        .into_offset_iter()
+        .filter(|&(_, ref range)| range.start > text.len())
+        .filter_map(|(event, range)| match event {
+                        Event::Start(Tag::Link(LinkType::Shortcut, url, title)) => {
+                            Some(Definition {
+                                     label: probe[range.start + 1..range.end - 1].to_owned(),
+                                     url: url.into_string(),
+                                     title: title.into_string(),
+                                 })
+                        }
+                        _ => None,
+                    })
+        .collect()
+}
+
+/// Normalize a link label, so that labels matching the same definition are equal
+pub fn normalize_label(label: &str) -> String {
+    label.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Call `f` on every inline in some blocks, before any nested in it
+fn visit_inlines<F: FnMut(&mut Inline)>(blocks: &mut [Block], f: &mut F) {
+    fn visit<F: FnMut(&mut Inline)>(inlines: &mut [Inline], f: &mut F) {
+        for inline in inlines {
+            f(inline);
+            match *inline {
+                Inline::Emphasis(ref mut content) |
+                Inline::Strong(ref mut content) |
+                Inline::Strikethrough(ref mut content) |
+                Inline::Link { ref mut content, .. } => visit(content, f),
+                _ => (),
+            }
+        }
+    }
+    for block in blocks {
+        match *block {
+            Block::Paragraph(ref mut inlines) |
+            Block::Header(_, ref mut inlines) => visit(inlines, f),
+            Block::BlockQuote(ref mut blocks) |
+            Block::FootnoteDefinition { ref mut blocks, .. } => visit_inlines(blocks, f),
+            Block::List { ref mut items, .. } => {
+                for item in items {
+                    visit_inlines(item, f);
+                }
+            }
+            Block::Table { ref mut rows, .. } => {
+                for cell in rows.iter_mut().flat_map(|row| row) {
+                    visit(cell, f);
+                }
+            }
+            Block::CodeBlock { .. } |
+            Block::Html(_) |
+            Block::Rule => (),
+        }
+    }
+}
+
+/// Check if a footnote label is a plain number, which can be renumbered
+fn is_number(label: &str) -> bool {
+    !label.is_empty() && label.chars().all(|c| c.is_digit(10))
+}
+
+/// Remove footnote definitions from anywhere in some blocks, adding them to `definitions`
+fn take_definitions(blocks: &mut Vec<Block>, definitions: &mut Vec<(String, Vec<Block>)>) {
+    let mut i = 0;
+    while i < blocks.len() {
+        match blocks[i] {
+            Block::FootnoteDefinition { .. } => {
+                if let Block::FootnoteDefinition { label, mut blocks } = blocks.remove(i) {
+                    // The parser nests definitions that follow one another, so any in this one
+                    // are taken out after it
+                    let mut nested = Vec::new();
+                    take_definitions(&mut blocks, &mut nested);
+                    definitions.push((label, blocks));
+                    definitions.extend(nested);
+                }
+                continue;
+            }
+            Block::BlockQuote(ref mut blocks) => take_definitions(blocks, definitions),
+            Block::List { ref mut items, .. } => {
+                for item in items {
+                    take_definitions(item, definitions);
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+}
+
+/// Split footnote definitions written on the lines straight after others back off them, as the
+/// parser reads them as more of the paragraph ending the one before
+fn split_definitions(blocks: &mut Vec<Block>) {
+    let mut i = 0;
+    while i < blocks.len() {
+        let mut split = None;
+        match blocks[i] {
+            Block::FootnoteDefinition { ref mut blocks, .. } => {
+                split_definitions(blocks);
+                if let Some(&mut Block::Paragraph(ref mut inlines)) = blocks.last_mut() {
+                    let start = (0..inlines.len()).find(|&j| match inlines[j..] {
+                        [Inline::SoftBreak, Inline::FootnoteReference(_), Inline::Text(ref text),
+                         ..] => text.starts_with(':'),
+                        _ => false,
+                    });
+                    if let Some(start) = start {
+                        let mut rest = inlines.split_off(start).into_iter().skip(1);
+                        let label = match rest.next() {
+                            Some(Inline::FootnoteReference(label)) => label,
+                            _ => unreachable!(),
+                        };
+                        let mut content: Vec<Inline> = rest.collect();
+                        if let Inline::Text(text) = content.remove(0) {
+                            let text = text[1..].trim_left();
+                            if !text.is_empty() {
+                                content.insert(0, Inline::Text(text.to_owned()));
+                            }
+                        }
+                        split = Some(Block::FootnoteDefinition {
+                                         label,
+                                         blocks: if content.is_empty() {
+                                             Vec::new()
+                                         } else {
+                                             vec![Block::Paragraph(content)]
+                                         },
+                                     });
+                    }
+                }
+            }
+            Block::BlockQuote(ref mut blocks) => split_definitions(blocks),
+            Block::List { ref mut items, .. } => {
+                for item in items {
+                    split_definitions(item);
+                }
+            }
+            _ => (),
+        }
+        // The definition split off is looked at next, in case more follow it
+        if let Some(definition) = split {
+            blocks.insert(i + 1, definition);
+        }
+        i += 1;
+    }
+}
+
+/// Add the labels of footnotes referenced or defined in some blocks to `labels`, in order
+fn footnote_labels(blocks: &[Block], labels: &mut Vec<String>) {
+    fn inline_labels(inlines: &[Inline], labels: &mut Vec<String>) {
+        for inline in inlines {
+            match *inline {
+                Inline::FootnoteReference(ref label) => {
+                    if !labels.contains(label) {
+                        labels.push(label.clone());
+                    }
+                }
+                Inline::Emphasis(ref content) |
+                Inline::Strong(ref content) |
+                Inline::Strikethrough(ref content) |
+                Inline::Link { ref content, .. } => inline_labels(content, labels),
+                _ => (),
+            }
+        }
+    }
+    for block in blocks {
+        match *block {
+            Block::Paragraph(ref inlines) |
+            Block::Header(_, ref inlines) => inline_labels(inlines, labels),
+            Block::BlockQuote(ref blocks) => footnote_labels(blocks, labels),
+            Block::List { ref items, .. } => {
+                for item in items {
+                    footnote_labels(item, labels);
+                }
+            }
+            Block::FootnoteDefinition {
+                ref label,
+                ref blocks,
+            } => {
+                if !labels.contains(label) {
+                    labels.push(label.clone());
+                }
+                footnote_labels(blocks, labels);
+            }
+            Block::Table { ref rows, .. } => {
+                for cell in rows.iter().flat_map(|row| row) {
+                    inline_labels(cell, labels);
+                }
+            }
+            Block::CodeBlock { .. } |
+            Block::Html(_) |
+            Block::Rule => (),
+        }
+    }
+}
+
+/// Rename the footnotes referenced or defined in some blocks
+fn rename_footnotes(blocks: &mut [Block], renamed: &HashMap<String, String>) {
+    fn rename_inlines(inlines: &mut [Inline], renamed: &HashMap<String, String>) {
+        for inline in inlines {
+            match *inline {
+                Inline::FootnoteReference(ref mut label) => {
+                    if let Some(new) = renamed.get(label) {
+                        *label = new.clone();
+                    }
+                }
+                Inline::Emphasis(ref mut content) |
+                Inline::Strong(ref mut content) |
+                Inline::Strikethrough(ref mut content) |
+                Inline::Link { ref mut content, .. } => rename_inlines(content, renamed),
+                _ => (),
+            }
+        }
+    }
+    for block in blocks {
+        match *block {
+            Block::Paragraph(ref mut inlines) |
+            Block::Header(_, ref mut inlines) => rename_inlines(inlines, renamed),
+            Block::BlockQuote(ref mut blocks) => rename_footnotes(blocks, renamed),
+            Block::List { ref mut items, .. } => {
+                for item in items {
+                    rename_footnotes(item, renamed);
+                }
+            }
+            Block::FootnoteDefinition {
+                ref mut label,
+                ref mut blocks,
+            } => {
+                if let Some(new) = renamed.get(label) {
+                    *label = new.clone();
+                }
+                rename_footnotes(blocks, renamed);
+            }
+            Block::Table { ref mut rows, .. } => {
+                for cell in rows.iter_mut().flat_map(|row| row) {
+                    rename_inlines(cell, renamed);
+                }
+            }
+            Block::CodeBlock { .. } |
+            Block::Html(_) |
+            Block::Rule => (),
+        }
+    }
+}
+
+/// Turn URLs written out in text into links, as GitHub does
+fn autolink(inlines: Vec<Inline>) -> Vec<Inline> {
+    let mut linked = Vec::with_capacity(inlines.len());
+    for inline in inlines {
+        match inline {
+            Inline::Text(text) => {
+                let mut rest = &text[..];
+                while let Some((start, end)) = find_url(rest) {
+                    if start > 0 {
+                        push_inline(&mut linked, Inline::Text(rest[..start].to_owned()));
+                    }
+                    let url = &rest[start..end];
+                    linked.push(Inline::Link {
+                                    url: if url.starts_with("www.") {
+                                        format!("http://{}", url)
+                                    } else {
+                                        url.to_owned()
+                                    },
+                                    title: String::new(),
+                                    content: vec![Inline::Text(url.to_owned())],
+                                    reference: None,
+                                });
+                    rest = &rest[end..];
+                }
+                if !rest.is_empty() {
+                    push_inline(&mut linked, Inline::Text(rest.to_owned()));
+                }
+            }
+            Inline::Emphasis(content) => linked.push(Inline::Emphasis(autolink(content))),
+            Inline::Strong(content) => linked.push(Inline::Strong(autolink(content))),
+            Inline::Strikethrough(content) => {
+                linked.push(Inline::Strikethrough(autolink(content)))
+            }
+            inline => linked.push(inline),
+        }
+    }
+    linked
+}
+
+/// Find the first URL written out in `text` that GitHub would link, giving its bounds
+fn find_url(text: &str) -> Option<(usize, usize)> {
+    let mut from = 0;
+    loop {
+        let start = match ["http://", "https://", "www."]
+                  .iter()
+                  .filter_map(|prefix| {
+                                  text[from..]
+                                      .find(prefix)
+                                      .map(|i| (from + i, from + i + prefix.len()))
+                              })
+                  .min() {
+            Some(start) => start,
+            None => return None,
+        };
+        let (start, domain) = start;
+        from = domain;
+        // URLs must start a word
+        if text[..start]
+               .chars()
+               .next_back()
+               .map_or(false, |c| !c.is_whitespace() && !"*_~(".contains(c)) {
+            continue;
+        }
+        let mut end = text[domain..]
+            .find(|c: char| c.is_whitespace() || c == '<')
+            .map_or(text.len(), |i| domain + i);
+        // Trailing punctuation and unbalanced closing parentheses are left out
+        loop {
+            let url = &text[start..end];
+            match url.chars().next_back() {
+                Some(c) if "?!.,:*_~'\"".contains(c) => end -= 1,
+                Some(')') if url.matches(')').count() > url.matches('(').count() => end -= 1,
+                _ => break,
+            }
+        }
+        if text[domain..end].starts_with(|c: char| c.is_alphanumeric()) {
+            return Some((start, end));
+        }
+    }
+}
+
+/// Prefix every line of `text`, using `first` for the first line
+fn indent(text: &str, first: &str, rest: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let prefix = if i == 0 { first } else { rest };
+        if line.is_empty() {
+            out.push_str(prefix.trim_right());
+        } else {
+            out.push_str(prefix);
+            out.push_str(line);
+        }
+    }
+    out
+}
+
+fn blocks_to_markdown(blocks: &[Block], tight: bool) -> String {
+    let separator = if tight { "\n" } else { "\n\n" };
+    let mut last_list = None;
+    let mut alternate = false;
+    let blocks: Vec<String> = blocks
+        .iter()
+        .map(|block| {
+            // Adjacent lists using the same marker would be read back as one list
+            if let Block::List { start, .. } = *block {
+                let ordered = start.is_some();
+                alternate = last_list == Some(ordered) && !alternate;
+                last_list = Some(ordered);
+            } else {
+                last_list = None;
+                alternate = false;
+            }
+            block_to_markdown(block, alternate)
+        })
+        .collect();
+    blocks.join(separator)
+}
+
+fn block_to_markdown(block: &Block, alternate: bool) -> String {
+    match *block {
+        Block::Paragraph(ref inlines) => inlines_to_markdown(inlines, true),
+        // Headings spanning several lines can only be written as setext headings
+        Block::Header(level, ref inlines) if level <= 2 && plain_text(inlines).contains('\n') => {
+            let underline = if level == 1 { "===" } else { "---" };
+            format!("{}\n{}", inlines_to_markdown(inlines, true), underline)
+        }
+        Block::Header(level, ref inlines) => {
+            let mut text = inlines_to_markdown(inlines, false);
+            // A trailing run of `#` would be read as a closing sequence
+            if text.ends_with('#') {
+                let run = text.trim_right_matches('#').len();
+                text.insert(run, '\\');
+            }
+            format!("{} {}", "#".repeat(level as usize), text)
+        }
+        Block::CodeBlock { ref info, ref text } => {
+            let newline = if text.is_empty() || text.ends_with('\n') {
+                ""
+            } else {
+                "\n"
+            };
+            // Tildes are used when the info string itself contains backticks
+            let fence_char = if info.contains('`') { '~' } else { '`' };
+            let fence = repeat(fence_char)
+                .take(max(3, longest_run(text, fence_char) + 1))
+                .collect::<String>();
+            format!("{}{}\n{}{}{}", fence, info, text, newline, fence)
+        }
+        Block::Html(ref html) => html.trim_right_matches('\n').to_owned(),
+        // `---` straight after a paragraph would be read as the underline of a heading
+        Block::Rule => "***".to_owned(),
+        Block::BlockQuote(ref blocks) => indent(&blocks_to_markdown(blocks, false), "> ", "> "),
+        Block::List {
+            start,
+            tight,
+            ref items,
+        } => {
+            let items: Vec<String> = items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| {
+                    let marker = match start {
+                        Some(start) => {
+                            format!("{}{} ", start + i, if alternate { ')' } else { '.' })
+                        }
+                        None => if alternate { "* " } else { "- " }.to_owned(),
+                    };
+                    let rest = " ".repeat(marker.len());
+                    let mut text = blocks_to_markdown(item, tight);
+                    // `* ***` would be read as a rule rather than an item holding one
+                    if start.is_none() && alternate && item.first() == Some(&Block::Rule) {
+                        text = text.replacen("***", "___", 1);
+                    }
+                    indent(&text, &marker, &rest)
+                })
+                .collect();
+            items.join(if tight { "\n" } else { "\n\n" })
+        }
+        Block::FootnoteDefinition {
+            ref label,
+            ref blocks,
+        } => {
+            let marker = format!("[^{}]: ", label);
+            indent(&blocks_to_markdown(blocks, false), &marker, "    ")
+                .trim_right()
+                .to_owned()
+        }
+        Block::Table {
+            ref alignments,
+            ref rows,
+        } => table_to_markdown(alignments, rows),
+    }
+}
+
+/// Serialize a table as a pipe table with its columns lined up
+fn table_to_markdown(alignments: &[Alignment], rows: &[Vec<Vec<Inline>>]) -> String {
+    let cells: Vec<Vec<String>> = rows.iter()
+        .map(|row| {
+                 row.iter()
+                     .map(|cell| escape_pipes(&inlines_to_markdown(cell, false)))
+                     .collect()
+             })
+        .collect();
+    let columns = max(alignments.len(),
+                      cells.iter().map(Vec::len).max().unwrap_or(0));
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            cells.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.chars().count())
+                .fold(3, max)
+        })
+        .collect();
+    let alignment = |column: usize| alignments.get(column).cloned().unwrap_or(Alignment::None);
+    let row_to_markdown = |row: &[String]| {
+        let cells: Vec<String> = widths
+            .iter()
+            .enumerate()
+            .map(|(column, &width)| {
+                let cell = row.get(column).map(String::as_str).unwrap_or("");
+                let padding = width - cell.chars().count();
+                let before = match alignment(column) {
+                    Alignment::Right => padding,
+                    Alignment::Center => padding / 2,
+                    Alignment::Left | Alignment::None => 0,
+                };
+                format!("{}{}{}", " ".repeat(before), cell, " ".repeat(padding - before))
+            })
+            .collect();
+        format!("| {} |", cells.join(" | "))
+    };
+    let delimiters: Vec<String> = widths
+        .iter()
+        .enumerate()
+        .map(|(column, &width)| match alignment(column) {
+                 Alignment::Left => format!(":{}", "-".repeat(width - 1)),
+                 Alignment::Center => format!(":{}:", "-".repeat(width - 2)),
+                 Alignment::Right => format!("{}:", "-".repeat(width - 1)),
+                 Alignment::None => "-".repeat(width),
+             })
+        .collect();
+    let mut lines = Vec::with_capacity(rows.len() + 1);
+    let mut cells = cells.iter();
+    lines.push(row_to_markdown(cells.next().map(Vec::as_slice).unwrap_or(&[])));
+    lines.push(format!("| {} |", delimiters.join(" | ")));
+    lines.extend(cells.map(|row| row_to_markdown(row)));
+    lines.join("\n")
+}
+
+/// Escape the pipes in a table cell's Markdown that aren't escaped already. Backslashes in text
+/// are all escaped by then, but code spans keep theirs, and a pipe after an odd number of them
+/// is already escaped from the table.
+fn escape_pipes(markdown: &str) -> String {
+    let mut escaped = String::with_capacity(markdown.len());
+    let mut backslashes = 0;
+    for ch in markdown.chars() {
+        if ch == '|' && backslashes % 2 == 0 {
+            escaped.push('\\');
+        }
+        backslashes = if ch == '\\' { backslashes + 1 } else { 0 };
+        escaped.push(ch);
+    }
+    escaped
+}
+
+/// Serialize inlines, where `line_start` is whether they begin a line
+fn inlines_to_markdown(inlines: &[Inline], line_start: bool) -> String {
+    let mut text = String::new();
+    // Whether the last inline was written as a shortcut reference, `[label]`
+    let mut shortcut = false;
+    for inline in inlines {
+        let after_shortcut = shortcut;
+        shortcut = false;
+        match *inline {
+            Inline::Text(ref t) => {
+                let line_start = if text.is_empty() {
+                    line_start
+                } else {
+                    text.ends_with('\n')
+                };
+                // A shortcut reference followed by `(` would be read as an inline link
+                if after_shortcut && t.starts_with('(') {
+                    text.push('\\');
+                }
+                text.push_str(&escape_text(t, line_start));
+            }
+            Inline::Html(ref html) => text.push_str(html),
+            Inline::Code(ref code) => {
+                let fence = "`".repeat(longest_run(code, '`') + 1);
+                // Stops backticks at either end from joining onto the fence, and spaces at both
+                // ends from being taken as padding themselves
+                let padding = if code.is_empty() || code.starts_with('`') || code.ends_with('`') ||
+                                 (code.starts_with(' ') && code.ends_with(' ') &&
+                                  !code.trim().is_empty()) {
+                    " "
+                } else {
+                    ""
+                };
+                text.push_str(&fence);
+                text.push_str(padding);
+                text.push_str(code);
+                text.push_str(padding);
+                text.push_str(&fence);
+            }
+            Inline::Emphasis(ref content) => {
+                let content = inlines_to_markdown(content, false);
+                // `*` next to another emphasis would be read as strong emphasis
+                let delimiter = if content.starts_with('*') || content.ends_with('*') {
+                    "_"
+                } else {
+                    "*"
+                };
+                text.push_str(&delimit(&content, delimiter));
+            }
+            Inline::Strong(ref content) => {
+                text.push_str(&delimit(&inlines_to_markdown(content, false), "**"));
+            }
+            Inline::Strikethrough(ref content) => {
+                text.push_str(&delimit(&inlines_to_markdown(content, false), "~~"));
+            }
+            Inline::TaskMarker(checked) => text.push_str(if checked { "[x] " } else { "[ ] " }),
+            Inline::FootnoteReference(ref label) => {
+                text.push_str("[^");
+                text.push_str(label);
+                text.push(']');
+            }
+            // Links to themselves are written as autolinks
+            Inline::Link {
+                ref url,
+                ref title,
+                ref content,
+                reference: None,
+            } if title.is_empty() && is_autolink(url) &&
+                 *content == [Inline::Text(url.clone())] => {
+                text.push('<');
+                text.push_str(url);
+                text.push('>');
+            }
+            Inline::Link {
+                ref url,
+                ref title,
+                ref content,
+                ref reference,
+            } => {
+                let content = inlines_to_markdown(content, false);
+                shortcut = push_link(&mut text, &content, url, title, reference);
+            }
+            Inline::Image {
+                ref url,
+                ref title,
+                ref alt,
+                ref reference,
+            } => {
+                text.push('!');
+                shortcut = push_link(&mut text, &escape_text(alt, false), url, title, reference);
+            }
+            Inline::SoftBreak => text.push('\n'),
+            Inline::HardBreak => text.push_str("\\\n"),
+        }
+    }
+    text
+}
+
+/// Escape any characters in `text` that would otherwise be read as Markdown syntax
+fn escape_text(text: &str, line_start: bool) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut escaped = String::with_capacity(text.len());
+    for (i, &ch) in chars.iter().enumerate() {
+        let prev = if i == 0 { None } else { Some(chars[i - 1]) };
+        let next = chars.get(i + 1).cloned();
+        // Line breaks and whitespace starting a line would be taken as layout, so are written
+        // as character references
+        if ch == '\n' || (line_start && i == 0 && (ch == ' ' || ch == '\t')) {
+            escaped.push_str(&format!("&#{};", ch as u32));
+            continue;
+        }
+        let escape = match ch {
+            '\\' | '`' | '*' | '[' | ']' | '~' => true,
+            '_' => {
+                !(prev.map_or(false, char::is_alphanumeric) &&
+                  next.map_or(false, char::is_alphanumeric))
+            }
+            '&' => next.map_or(false, |c| c == '#' || c.is_alphanumeric()),
+            '<' => next.map_or(false, |c| c == '/' || c == '!' || c == '?' || c.is_alphabetic()),
+            // Might start an image when followed by a link
+            '!' => next.is_none(),
+            '#' | '>' | '-' | '+' | '=' => line_start && i == 0,
+            // Might make the line an ordered list item
+            '.' | ')' => line_start && i > 0 && i < 10 && chars[..i].iter().all(|c| c.is_digit(10)),
+            _ => false,
+        };
+        if escape {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+/// Check if `url` can be written as an autolink, `<url>`
+fn is_autolink(url: &str) -> bool {
+    if url.contains(|c: char| c.is_whitespace() || c.is_control() || c == '<' || c == '>') {
+        return false;
+    }
+    match url.find(':') {
+        Some(colon) => {
+            let scheme = &url[..colon];
+            scheme.len() >= 2 && scheme.len() <= 32 &&
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic()) &&
+            scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+.-".contains(c))
+        }
+        // Email addresses
+        None => {
+            url.matches('@').count() == 1 &&
+            url.chars()
+                .all(|c| c.is_ascii_alphanumeric() || ".!#$%&'*+/=?^_`{|}~-@".contains(c))
+        }
+    }
+}
+
+/// Find the length of the longest run of `ch` in `text`
+fn longest_run(text: &str, ch: char) -> usize {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        if c == ch {
+            run += 1;
+            longest = max(longest, run);
+        } else {
+            run = 0;
+        }
+    }
+    longest
+}
+
+/// Wrap `content` in `delimiter`, keeping any surrounding whitespace outside of it
+fn delimit(content: &str, delimiter: &str) -> String {
+    let start = content.len() - content.trim_left().len();
+    let end = content.trim_right().len();
+    if start >= end {
+        return content.to_owned();
+    }
+    format!("{}{}{}{}{}",
+            &content[..start],
+            delimiter,
+            &content[start..end],
+            delimiter,
+            &content[end..])
+}
+
+/// Write a link or image after its `!`, with `content` as its text, returning whether it was
+/// written as a shortcut reference
+fn push_link(text: &mut String,
+             content: &str,
+             url: &str,
+             title: &str,
+             reference: &Option<Reference>)
+             -> bool {
+    text.push('[');
+    text.push_str(content);
+    text.push(']');
+    // The shorter forms only work while the text still matches the label
+    let matches = |label: &str| normalize_label(content) == normalize_label(label);
+    match *reference {
+        None => text.push_str(&destination(url, title)),
+        Some(Reference::Shortcut(ref label)) if matches(label) => return true,
+        Some(Reference::Collapsed(ref label)) if matches(label) => text.push_str("[]"),
+        Some(ref reference) => {
+            text.push('[');
+            text.push_str(reference.label());
+            text.push(']');
+        }
+    }
+    false
+}
+
+/// Format the `(url "title")` part of a link or image
+fn destination(url: &str, title: &str) -> String {
+    format!("({})", link_target(url, title))
+}
+
+/// Format the `url "title"` of a link, image or definition
+fn link_target(url: &str, title: &str) -> String {
+    let url = url.replace('\\', "\\\\");
+    let bracketed = url.is_empty() ||
+                    url.contains(|c: char| c.is_whitespace() || c == '(' || c == ')');
+    let url = if bracketed {
+        format!("<{}>", url.replace('<', "\\<").replace('>', "\\>"))
+    } else {
+        url.to_owned()
+    };
+    if title.is_empty() {
+        url
+    } else {
+        let title = title.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("{} \"{}\"", url, title)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pulldown_cmark::html;
+
+    /// The examples from the CommonMark spec, with the arrows standing for tabs put back
+    fn spec_examples() -> Vec<String> {
+        let fence = "```````````````````````````````` example\n";
+        include_str!("../specs/spec.txt")
+            .split(fence)
+            .skip(1)
+            .map(|rest| {
+                     let end = if rest.starts_with(".\n") {
+                         0
+                     } else {
+                         rest.find("\n.\n").unwrap() + 1
+                     };
+                     rest[..end].replace('→', "\t")
+                 })
+            .collect()
+    }
+
+    fn to_html(text: &str) -> String {
+        let mut html = String::new();
+        html::push_html(&mut html, Parser::new_ext(text, options()));
+        html
+    }
+
+    fn text(text: &str) -> Inline {
+        Inline::Text(text.to_owned())
+    }
+
+    fn paragraph(inlines: Vec<Inline>) -> Document {
+        Document {
+            blocks: vec![Block::Paragraph(inlines)],
+            definitions: Vec::new(),
+        }
+    }
+
+    /// Check a document is written as the text given, and that the text is read back as it
+    fn assert_round_trip(document: &Document, markdown: &str) {
+        assert_eq!(document.to_markdown(), markdown);
+        assert_eq!(Document::parse(markdown), *document);
+    }
+
+    #[test]
+    fn inline_html_is_written_as_is() {
+        let mut inlines = InlineBuilder::new();
+        inlines.push_text(&[], "press ");
+        inlines.push(&[], Inline::Html("<kbd>".to_owned()));
+        inlines.push_text(&[], "Ctrl");
+        inlines.push(&[], Inline::Html("</kbd>".to_owned()));
+        assert_round_trip(&paragraph(inlines.finish()), "press <kbd>Ctrl</kbd>\n");
+    }
+
+    #[test]
+    fn html_blocks_are_written_as_is() {
+        let document = Document {
+            blocks: vec![Block::Html("<details>\n<!-- note -->\n</details>\n".to_owned()),
+                         Block::Paragraph(vec![text("after")])],
+            definitions: Vec::new(),
+        };
+        assert_round_trip(&document, "<details>\n<!-- note -->\n</details>\n\nafter\n");
+    }
+
+    #[test]
+    fn hard_breaks_stay_in_the_paragraph() {
+        let mut inlines = InlineBuilder::new();
+        inlines.push_text(&[], "a");
+        inlines.push(&[], Inline::HardBreak);
+        inlines.push_text(&[], "b");
+        assert_round_trip(&paragraph(inlines.finish()), "a\\\nb\n");
+    }
+
+    #[test]
+    fn images_keep_the_link_they_are_in() {
+        let link = Style::Link("ci".to_owned(), String::new(), None);
+        let mut inlines = InlineBuilder::new();
+        inlines.push(&[link],
+                     Inline::Image {
+                         url: "img".to_owned(),
+                         title: String::new(),
+                         alt: "b".to_owned(),
+                         reference: None,
+                     });
+        assert_round_trip(&paragraph(inlines.finish()), "[![b](img)](ci)\n");
+    }
+
+    #[test]
+    fn styles_nest_from_outermost_to_innermost() {
+        let mut inlines = InlineBuilder::new();
+        inlines.push_text(&[Style::Strong], "a ");
+        inlines.push_text(&[Style::Strong, Style::Emphasis], "b");
+        inlines.push_text(&[], " c");
+        assert_eq!(inlines.finish(),
+                   vec![Inline::Strong(vec![text("a "), Inline::Emphasis(vec![text("b")])]),
+                        text(" c")]);
+    }
+
+    #[test]
+    fn blocks_are_put_in_their_containers() {
+        let in_list = [Container::List {
+                           id: 1,
+                           start: None,
+                           tight: true,
+                       }];
+        let in_quote = [Container::BlockQuote { id: 2 }];
+        let mut blocks = BlockBuilder::new();
+        blocks.item(&in_list);
+        blocks.push(&in_list, Block::Paragraph(vec![text("one")]));
+        blocks.item(&in_list);
+        blocks.push(&in_list, Block::Paragraph(vec![text("two")]));
+        blocks.push(&in_quote, Block::Paragraph(vec![text("after")]));
+        let document = Document {
+            blocks: blocks.finish(),
+            definitions: Vec::new(),
+        };
+        assert_round_trip(&document, "- one\n- two\n\n> after\n");
+    }
+
+    #[test]
+    fn spec_examples_render_the_same_after_saving() {
+        let failed: Vec<usize> = spec_examples()
+            .iter()
+            .enumerate()
+            .filter(|&(_, example)| {
+                let mut document = Document::parse(example);
+                // URLs written out in text are linked like GitHub does, which the HTML renderer
+                // doesn't, so examples with them can't be compared
+                let mut links = 0;
+                visit_inlines(&mut document.blocks, &mut |inline| if let Inline::Link { .. } =
+                    *inline {
+                    links += 1;
+                });
+                let html = to_html(example);
+                links <= html.matches("<a ").count() &&
+                to_html(&document.to_markdown()) != html
+            })
+            .map(|(i, _)| i + 1)
+            .collect();
+        assert!(failed.is_empty(), "Examples {:?} changed", failed);
+    }
+
+    /// Lay inlines out flat as styled runs, like a view's buffer holds them, where a style
+    /// applies at most once
+    fn flatten_inlines(inlines: &[Inline], styles: &[Style], runs: &mut Vec<(Vec<Style>, Inline)>) {
+        for inline in inlines {
+            let with = |style: Style| {
+                let mut styles = styles.to_vec();
+                if !styles.contains(&style) {
+                    styles.push(style);
+                }
+                styles
+            };
+            match *inline {
+                Inline::Emphasis(ref content) => {
+                    flatten_inlines(content, &with(Style::Emphasis), runs)
+                }
+                Inline::Strong(ref content) => flatten_inlines(content, &with(Style::Strong), runs),
+                Inline::Strikethrough(ref content) => {
+                    flatten_inlines(content, &with(Style::Strikethrough), runs)
+                }
+                Inline::Code(ref code) => runs.push((with(Style::Code), text(code))),
+                Inline::Link {
+                    ref url,
+                    ref title,
+                    ref content,
+                    ref reference,
+                } => {
+                    let link = Style::Link(url.clone(), title.clone(), reference.clone());
+                    flatten_inlines(content, &with(link), runs)
+                }
+                ref inline => runs.push((styles.to_vec(), inline.clone())),
+            }
+        }
+    }
+
+    /// Lay blocks out flat in their containers, like a view's buffer holds them
+    fn flatten_blocks(blocks: &[Block],
+                      containers: &[Container],
+                      ids: &mut usize,
+                      builder: &mut BlockBuilder) {
+        for block in blocks {
+            *ids += 1;
+            let mut inner = containers.to_vec();
+            match *block {
+                Block::BlockQuote(ref blocks) => {
+                    inner.push(Container::BlockQuote { id: *ids });
+                    builder.item(&inner);
+                    flatten_blocks(blocks, &inner, ids, builder);
+                }
+                Block::FootnoteDefinition {
+                    ref label,
+                    ref blocks,
+                } => {
+                    inner.push(Container::FootnoteDefinition {
+                                   id: *ids,
+                                   label: label.clone(),
+                               });
+                    builder.item(&inner);
+                    flatten_blocks(blocks, &inner, ids, builder);
+                }
+                Block::List {
+                    start,
+                    tight,
+                    ref items,
+                } => {
+                    inner.push(Container::List {
+                                   id: *ids,
+                                   start,
+                                   tight,
+                               });
+                    for item in items {
+                        builder.item(&inner);
+                        flatten_blocks(item, &inner, ids, builder);
+                    }
+                }
+                Block::Paragraph(ref inlines) => {
+                    builder.push(containers, Block::Paragraph(read_back_inlines(inlines)))
+                }
+                Block::Header(level, ref inlines) => {
+                    builder.push(containers, Block::Header(level, read_back_inlines(inlines)))
+                }
+                ref block => builder.push(containers, block.clone()),
+            }
+        }
+    }
+
+    /// Build inlines back up from their styled runs, where a buffer's tags always give the
+    /// styles in the same order
+    fn read_back_inlines(inlines: &[Inline]) -> Vec<Inline> {
+        let mut runs = Vec::new();
+        flatten_inlines(inlines, &[], &mut runs);
+        let mut builder = InlineBuilder::new();
+        for (mut styles, inline) in runs {
+            styles.sort_by_key(|style| match *style {
+                                   Style::Link(..) => 0,
+                                   Style::Strong => 1,
+                                   Style::Emphasis => 2,
+                                   Style::Strikethrough => 3,
+                                   Style::Code => 4,
+                               });
+            builder.push(&styles, inline);
+        }
+        builder.finish()
+    }
+
+    /// Read a document back out of a view it was shown in, by way of the builders
+    fn read_back(document: &Document) -> Document {
+        let mut builder = BlockBuilder::new();
+        flatten_blocks(&document.blocks, &[], &mut 0, &mut builder);
+        Document {
+            blocks: builder.finish(),
+            definitions: document.definitions.clone(),
+        }
+    }
+
+    #[test]
+    fn unedited_documents_save_as_they_were_loaded() {
+        for example in spec_examples() {
+            // What was loaded is kept as it was read back out of the view it was shown in
+            let original = Original {
+                text: example.clone(),
+                document: read_back(&Document::parse(&example)),
+            };
+            let mut document = read_back(&Document::parse(&example));
+            assert_eq!(original.unedited(&document, false), Some(example.as_str()));
+            document.blocks.push(Block::Paragraph(vec![text("edit")]));
+            assert_eq!(original.unedited(&document, true), None);
+            // Edits that are undone leave the document as it was
+            document.blocks.pop();
+            assert_eq!(original.unedited(&document, true), Some(example.as_str()));
+        }
+    }
+
+    #[test]
+    fn edited_documents_are_written_out() {
+        let loaded = "Some  *text*\r\nwritten   _oddly_\n";
+        let original = Original {
+            text: loaded.to_owned(),
+            document: Document::parse(loaded),
+        };
+        let mut document = Document::parse(loaded);
+        document.blocks.push(Block::Paragraph(vec![text("more")]));
+        assert_eq!(original.unedited(&document, true), None);
+        assert_eq!(document.to_markdown(), "Some  *text*\nwritten   *oddly*\n\nmore\n");
+    }
+
+    #[test]
+    fn consecutive_footnote_definitions_are_flattened() {
+        let mut document = Document::parse("A[^2] B[^1]\n\n[^1]: one\n[^2]: two\n");
+        document.collect_footnotes();
+        assert_eq!(document.to_markdown(), "A[^1] B[^2]\n\n[^1]: two\n\n[^2]: one\n");
+    }
+
+    #[test]
+    fn rules_after_paragraphs_in_tight_lists_stay_rules() {
+        let document = Document::parse("- foo\n  ***\n- bar\n");
+        assert_eq!(document.blocks,
+                   vec![Block::List {
+                            start: None,
+                            tight: true,
+                            items: vec![vec![Block::Paragraph(vec![text("foo")]), Block::Rule],
+                                        vec![Block::Paragraph(vec![text("bar")])]],
+                        }]);
+        assert_round_trip(&document, "- foo\n  ***\n- bar\n");
+    }
+
+    fn table(alignments: Vec<Alignment>, rows: Vec<Vec<Vec<Inline>>>) -> Document {
+        Document {
+            blocks: vec![Block::Table { alignments, rows }],
+            definitions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn pipes_in_table_text_are_escaped() {
+        let document = table(vec![Alignment::None, Alignment::None],
+                             vec![vec![vec![text("a|b")], vec![text("c\\|d")]],
+                                  vec![vec![text("|")], vec![text("e\\")]]]);
+        assert_round_trip(&document,
+                          "| a\\|b | c\\\\\\|d |\n| ---- | ------ |\n| \\|   | e\\\\    |\n");
+    }
+
+    #[test]
+    fn pipes_in_table_code_spans_are_escaped_once() {
+        let markdown = "| `x\\|y` | `z\\\\\\|w` |\n| ------ | -------- |\n";
+        let document = Document::parse(markdown);
+        assert_eq!(document.blocks,
+                   table(vec![Alignment::None, Alignment::None],
+                         vec![vec![vec![Inline::Code("x\\|y".to_owned())],
+                                   vec![Inline::Code("z\\\\\\|w".to_owned())]]])
+                           .blocks);
+        assert_round_trip(&document, markdown);
+        // Pipes that weren't read from a table are escaped from it
+        let document = table(vec![Alignment::None],
+                             vec![vec![vec![Inline::Code("a|b".to_owned())]]]);
+        assert_eq!(document.to_markdown(), "| `a\\|b` |\n| ------ |\n");
+    }
+
+    #[test]
+    fn table_columns_keep_their_alignment() {
+        let document = table(vec![Alignment::None,
+                                  Alignment::Left,
+                                  Alignment::Center,
+                                  Alignment::Right],
+                             vec![vec![vec![text("a")],
+                                       vec![text("b")],
+                                       vec![text("c")],
+                                       vec![text("d")]],
+                                  vec![vec![text("one")],
+                                       vec![text("two")],
+                                       vec![text("three")],
+                                       vec![text("four")]]]);
+        assert_round_trip(&document,
+                          "| a   | b   |   c   |    d |\n| --- | :-- | :---: | ---: |\n\
+                           | one | two | three | four |\n");
+    }
+
+    #[test]
+    fn code_spans_are_fenced_past_their_backticks() {
+        assert_round_trip(&paragraph(vec![Inline::Code("a`b".to_owned())]), "``a`b``\n");
+        assert_round_trip(&paragraph(vec![Inline::Code("`a``".to_owned())]), "``` `a`` ```\n");
+        assert_round_trip(&paragraph(vec![text("x "), Inline::Code("*y*".to_owned())]),
+                          "x `*y*`\n");
+    }
+
+    #[test]
+    fn code_blocks_keep_their_info_strings() {
+        let code = |info: &str, text: &str| {
+            Document {
+                blocks: vec![Block::CodeBlock {
+                                 info: info.to_owned(),
+                                 text: text.to_owned(),
+                             }],
+                definitions: Vec::new(),
+            }
+        };
+        assert_round_trip(&code("rust", "fn main() {}\n"), "```rust\nfn main() {}\n```\n");
+        assert_round_trip(&code("md", "```\nnested\n````\n"),
+                          "`````md\n```\nnested\n````\n`````\n");
+        assert_round_trip(&code("a`b", "~~\n"), "~~~a`b\n~~\n~~~\n");
+        // Indented code is fenced, as it can't have an info string
+        assert_eq!(Document::parse("    indented\n").to_markdown(),
+                   "```\nindented\n```\n");
+    }
+
+    #[test]
+    fn headings_keep_their_levels() {
+        let headings = Document {
+            blocks: (1..7).map(|level| Block::Header(level, vec![text("title")])).collect(),
+            definitions: Vec::new(),
+        };
+        assert_round_trip(&headings,
+                          "# title\n\n## title\n\n### title\n\n#### title\n\n##### title\n\n\
+                           ###### title\n");
+        assert_eq!(Document::parse("Setext\n======\n\nTwo\n---\n").to_markdown(),
+                   "# Setext\n\n## Two\n");
+        // Only setext headings can go over several lines
+        assert_round_trip(&Document::parse("a\nb\n---\n"), "a\nb\n---\n");
+        assert_round_trip(&Document {
+                              blocks: vec![Block::Header(3, vec![text("C#")])],
+                              definitions: Vec::new(),
+                          },
+                          "### C\\#\n");
+    }
+
+    #[test]
+    fn lists_keep_their_numbering_nesting_and_spacing() {
+        assert_round_trip(&Document::parse("3. a\n4. b\n"), "3. a\n4. b\n");
+        assert_round_trip(&Document::parse("- a\n  1. b\n  2. c\n- d\n"),
+                          "- a\n  1. b\n  2. c\n- d\n");
+        let loose = Document::parse("- a\n\n- b\n");
+        match loose.blocks[0] {
+            Block::List { tight, .. } => assert!(!tight),
+            ref block => panic!("Expected a list, got {:?}", block),
+        }
+        assert_round_trip(&loose, "- a\n\n- b\n");
+        // Lists next to each other need different markers to stay apart
+        let list = |start| {
+            Block::List {
+                start,
+                tight: true,
+                items: vec![vec![Block::Paragraph(vec![text("a")])]],
+            }
+        };
+        assert_round_trip(&Document {
+                              blocks: vec![list(None), list(None), list(None)],
+                              definitions: Vec::new(),
+                          },
+                          "- a\n\n* a\n\n- a\n");
+        assert_round_trip(&Document {
+                              blocks: vec![list(Some(1)), list(Some(1))],
+                              definitions: Vec::new(),
+                          },
+                          "1. a\n\n1) a\n");
+    }
+
+    #[test]
+    fn block_quotes_keep_what_they_hold() {
+        assert_round_trip(&Document::parse("> a\n>\n> > b\n>\n> - c\n"),
+                          "> a\n>\n> > b\n>\n> - c\n");
+        // Lines carried on lazily are written inside the quote
+        assert_eq!(Document::parse("> a\nb\n").to_markdown(), "> a\n> b\n");
+        let quote = Block::BlockQuote(vec![Block::Paragraph(vec![text("a")])]);
+        assert_round_trip(&Document {
+                              blocks: vec![quote.clone(), quote],
+                              definitions: Vec::new(),
+                          },
+                          "> a\n\n> a\n");
+    }
+
+    #[test]
+    fn rules_are_kept_between_blocks() {
+        let document = Document::parse("a\n\n- - -\n\n___\nb\n");
+        assert_eq!(document.blocks,
+                   vec![Block::Paragraph(vec![text("a")]),
+                        Block::Rule,
+                        Block::Rule,
+                        Block::Paragraph(vec![text("b")])]);
+        assert_round_trip(&document, "a\n\n***\n\n***\n\nb\n");
+        // An item of a `*` list starting with a rule can't be written `* ***`
+        let list = |block| {
+            Block::List {
+                start: None,
+                tight: true,
+                items: vec![vec![block]],
+            }
+        };
+        assert_round_trip(&Document {
+                              blocks: vec![list(Block::Paragraph(vec![text("a")])),
+                                           list(Block::Rule)],
+                              definitions: Vec::new(),
+                          },
+                          "- a\n\n* ___\n");
+    }
+
+    #[test]
+    fn gfm_inline_extensions_are_kept() {
+        let document = Document::parse("~~gone~~ but \\~ kept\n");
+        assert_eq!(document.blocks,
+                   vec![Block::Paragraph(vec![Inline::Strikethrough(vec![text("gone")]),
+                                              text(" but ~ kept")])]);
+        assert_round_trip(&document, "~~gone~~ but \\~ kept\n");
+        let tasks = Document::parse("- [ ] to do\n- [x] done\n");
+        match tasks.blocks[0] {
+            Block::List { ref items, .. } => {
+                assert_eq!(items[1],
+                           vec![Block::Paragraph(vec![Inline::TaskMarker(true), text("done")])])
+            }
+            ref block => panic!("Expected a list, got {:?}", block),
+        }
+        assert_round_trip(&tasks, "- [ ] to do\n- [x] done\n");
+        // URLs in text are linked, and written as autolinks so they stay linked
+        let linked = Document::parse("see https://example.com/a_b.\n");
+        assert_eq!(linked.blocks,
+                   vec![Block::Paragraph(vec![text("see "),
+                                              Inline::Link {
+                                                  url: "https://example.com/a_b".to_owned(),
+                                                  title: String::new(),
+                                                  content: vec![text("https://example.com/a_b")],
+                                                  reference: None,
+                                              },
+                                              text(".")])]);
+        assert_round_trip(&linked, "see <https://example.com/a_b>.\n");
+    }
+
+    #[test]
+    fn footnotes_are_renumbered_in_the_order_they_are_referenced() {
+        let mut document = Document::parse("B[^x] A[^3]\n\n> C[^1]\n>\n> [^1]: one\n\n\
+                                            [^x]: named\n\n[^3]: three\n\n[^9]: unused\n");
+        let renamed = document.collect_footnotes();
+        assert_eq!(renamed.get("3").map(String::as_str), Some("1"));
+        assert_eq!(renamed.get("1").map(String::as_str), Some("2"));
+        assert_eq!(renamed.get("9").map(String::as_str), Some("3"));
+        assert!(!renamed.contains_key("x"));
+        assert_eq!(document.to_markdown(),
+                   "B[^x] A[^1]\n\n> C[^2]\n\n[^x]: named\n\n[^1]: three\n\n[^2]: one\n\n\
+                    [^3]: unused\n");
+        assert_eq!(document.next_footnote_label(), "4");
+    }
+
+    #[test]
+    fn reference_links_and_their_definitions_are_kept() {
+        let document = Document::parse("[full][Ref], [ref][], [ref] and ![alt][ref]\n\n\
+                                        [ref]: /url \"title\"\n[unused]: <a b>\n");
+        assert_eq!(document.definitions.len(), 2);
+        assert_eq!(document.definition("REF").map(|definition| definition.url.as_str()),
+                   Some("/url"));
+        let reference = |inline: &Inline| match *inline {
+            Inline::Link { ref reference, .. } |
+            Inline::Image { ref reference, .. } => reference.clone(),
+            _ => None,
+        };
+        match document.blocks[0] {
+            Block::Paragraph(ref inlines) => {
+                assert_eq!(reference(&inlines[0]), Some(Reference::Full("Ref".to_owned())));
+                assert_eq!(reference(&inlines[2]), Some(Reference::Collapsed("ref".to_owned())));
+                assert_eq!(reference(&inlines[4]), Some(Reference::Shortcut("ref".to_owned())));
+                assert_eq!(reference(&inlines[6]), Some(Reference::Full("ref".to_owned())));
+            }
+            ref block => panic!("Expected a paragraph, got {:?}", block),
+        }
+        assert_round_trip(&document,
+                          "[full][Ref], [ref][], [ref] and ![alt][ref]\n\n\
+                           [ref]: /url \"title\"\n[unused]: <a b>\n");
+    }
+
+    #[test]
+    fn links_keep_their_titles() {
+        let link = |url: &str, title: &str| {
+            paragraph(vec![Inline::Link {
+                               url: url.to_owned(),
+                               title: title.to_owned(),
+                               content: vec![text("a")],
+                               reference: None,
+                           }])
+        };
+        assert_round_trip(&link("/url", "title"), "[a](/url \"title\")\n");
+        assert_round_trip(&link("/a b(1)", "say \"hi\" \\ (ok)"),
+                          "[a](</a b(1)> \"say \\\"hi\\\" \\\\ (ok)\")\n");
+        assert_round_trip(&link("", ""), "[a](<>)\n");
+    }
+
+    #[test]
+    fn images_keep_their_alt_text_and_titles() {
+        let image = paragraph(vec![Inline::Image {
+                                       url: "a.png".to_owned(),
+                                       title: "A \"title\"".to_owned(),
+                                       alt: "x *y* [z]".to_owned(),
+                                       reference: None,
+                                   }]);
+        assert_round_trip(&image, "![x \\*y\\* \\[z\\]](a.png \"A \\\"title\\\"\")\n");
+        // Styles in alt text only show as plain text, so are kept as that
+        assert_eq!(Document::parse("![*a* `b`](c)\n").to_markdown(), "![a b](c)\n");
+    }
+}
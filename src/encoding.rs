@@ -0,0 +1,351 @@
+use std::fmt;
+use std::str;
+
+/// A character encoding documents can be read and written in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// The Windows take on Latin-1, which any bytes can be read as, so it is what documents that
+    /// aren't in any other encoding are read as
+    Windows1252,
+}
+
+/// Every encoding, in the order they are offered in
+pub const ENCODINGS: [Encoding; 4] = [Encoding::Utf8,
+                                      Encoding::Utf16Le,
+                                      Encoding::Utf16Be,
+                                      Encoding::Windows1252];
+
+/// The characters bytes `0x80` to `0x9F` stand for in Windows-1252, where the rest stand for
+/// the same as in Latin-1, as do the five bytes Windows-1252 leaves out
+const WINDOWS_1252: [char; 32] = ['\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}',
+                                  '\u{2026}', '\u{2020}', '\u{2021}', '\u{2C6}', '\u{2030}',
+                                  '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}',
+                                  '\u{8F}', '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}',
+                                  '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}', '\u{2DC}',
+                                  '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}',
+                                  '\u{17E}', '\u{178}'];
+
+impl Encoding {
+    /// The name the encoding goes by in the encoding selector
+    pub fn id(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Windows1252 => "windows-1252",
+        }
+    }
+    pub fn from_id(id: &str) -> Option<Encoding> {
+        ENCODINGS.iter().cloned().find(|encoding| encoding.id() == id)
+    }
+    /// Find the encoding a `charset` stands for, going by the labels browsers accept for it
+    pub fn for_label(label: &str) -> Option<Encoding> {
+        match label.trim().to_lowercase().as_str() {
+            "utf-8" | "utf8" | "unicode-1-1-utf-8" => Some(Encoding::Utf8),
+            "utf-16" | "utf-16le" | "unicode" | "ucs-2" => Some(Encoding::Utf16Le),
+            "utf-16be" | "unicodefffe" => Some(Encoding::Utf16Be),
+            "windows-1252" | "cp1252" | "x-cp1252" | "iso-8859-1" | "iso8859-1" | "iso_8859-1" |
+            "latin1" | "l1" | "cp819" | "ibm819" | "us-ascii" | "ascii" => {
+                Some(Encoding::Windows1252)
+            }
+            _ => None,
+        }
+    }
+    /// The byte order mark that starts documents in the encoding, if it has one
+    pub fn bom(self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8 => b"\xEF\xBB\xBF",
+            Encoding::Utf16Le => b"\xFF\xFE",
+            Encoding::Utf16Be => b"\xFE\xFF",
+            Encoding::Windows1252 => b"",
+        }
+    }
+    /// Read text in the encoding, unless it isn't valid in it
+    pub fn decode(self, bytes: &[u8]) -> Option<String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes.to_vec()).ok(),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                if bytes.len() % 2 != 0 {
+                    return None;
+                }
+                let units: Vec<u16> = bytes
+                    .chunks(2)
+                    .map(|pair| if self == Encoding::Utf16Le {
+                             pair[0] as u16 | (pair[1] as u16) << 8
+                         } else {
+                             (pair[0] as u16) << 8 | pair[1] as u16
+                         })
+                    .collect();
+                String::from_utf16(&units).ok()
+            }
+            Encoding::Windows1252 => {
+                Some(bytes
+                         .iter()
+                         .map(|&byte| match byte {
+                                  0x80..=0x9F => WINDOWS_1252[byte as usize - 0x80],
+                                  _ => byte as char,
+                              })
+                         .collect())
+            }
+        }
+    }
+    /// Write text in the encoding, or return the first character that can't be written in it
+    pub fn encode(self, text: &str) -> Result<Vec<u8>, char> {
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Utf16Le => {
+                Ok(text.encode_utf16()
+                       .flat_map(|unit| vec![unit as u8, (unit >> 8) as u8])
+                       .collect())
+            }
+            Encoding::Utf16Be => {
+                Ok(text.encode_utf16()
+                       .flat_map(|unit| vec![(unit >> 8) as u8, unit as u8])
+                       .collect())
+            }
+            Encoding::Windows1252 => {
+                text.chars()
+                    .map(|c| match c as u32 {
+                             0..=0x7F | 0xA0..=0xFF => Ok(c as u8),
+                             _ => {
+                                 WINDOWS_1252
+                                     .iter()
+                                     .position(|&special| special == c)
+                                     .map(|index| 0x80 + index as u8)
+                                     .ok_or(c)
+                             }
+                         })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+                        Encoding::Utf8 => "UTF-8",
+                        Encoding::Utf16Le => "UTF-16 (little-endian)",
+                        Encoding::Utf16Be => "UTF-16 (big-endian)",
+                        Encoding::Windows1252 => "Western (Windows-1252)",
+                    })
+    }
+}
+
+/// How a document was written, so it can be written back the same way
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Format {
+    pub encoding: Encoding,
+    /// Whether it starts with a byte order mark
+    pub bom: bool,
+    /// Whether its lines end with a carriage return then a line feed, like on Windows, rather
+    /// than just a line feed
+    pub crlf: bool,
+}
+
+impl Default for Format {
+    fn default() -> Format {
+        Format {
+            encoding: Encoding::Utf8,
+            bom: false,
+            crlf: false,
+        }
+    }
+}
+
+impl Format {
+    /// Write a document out in the format, or return the first character in it that can't be
+    /// written in its encoding
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, char> {
+        let mut bytes = if self.bom {
+            self.encoding.bom().to_vec()
+        } else {
+            Vec::new()
+        };
+        if self.crlf {
+            let text = text.replace("\r\n", "\n").replace('\n', "\r\n");
+            bytes.extend(self.encoding.encode(&text)?);
+        } else {
+            bytes.extend(self.encoding.encode(text)?);
+        }
+        Ok(bytes)
+    }
+}
+
+/// Find the `charset` parameter of a `Content-Type` header
+pub fn charset(content_type: &str) -> Option<&str> {
+    content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("charset") => {
+                    Some(value.trim().trim_matches('"'))
+                }
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Guess if text without a byte order mark is in UTF-16 by the zero bytes that ASCII characters
+/// leave in it, which are in every other byte and aren't in text in other encodings
+fn guess_utf16(bytes: &[u8]) -> Option<Encoding> {
+    let sample = &bytes[..bytes.len().min(1024)];
+    let zeros = |first: usize| {
+        sample
+            .iter()
+            .skip(first)
+            .step_by(2)
+            .filter(|&&byte| byte == 0)
+            .count()
+    };
+    let (even, odd) = (zeros(0), zeros(1));
+    let half = sample.len() / 2;
+    if odd > half / 2 && even == 0 {
+        Some(Encoding::Utf16Le)
+    } else if even > half / 2 && odd == 0 {
+        Some(Encoding::Utf16Be)
+    } else {
+        None
+    }
+}
+
+/// Read a document, working out what it was written in from its byte order mark, or failing
+/// that the `charset` it was said to be in, or failing that what it looks like
+///
+/// Documents are always read as something, as any bytes can be read as Windows-1252. Lines
+/// are given to the editor ending with just line feeds, unless their endings are mixed, when
+/// they are left as they are so the document can be written back unchanged.
+pub fn decode(bytes: &[u8], charset: Option<&str>) -> (String, Format) {
+    let marked = ENCODINGS
+        .iter()
+        .cloned()
+        .filter(|encoding| !encoding.bom().is_empty() && bytes.starts_with(encoding.bom()))
+        .filter_map(|encoding| {
+                        encoding
+                            .decode(&bytes[encoding.bom().len()..])
+                            .map(|text| (text, encoding, true))
+                    })
+        .next();
+    let declared = || {
+        charset
+            .and_then(Encoding::for_label)
+            // Servers often say text is in Latin-1 when it is in UTF-8, which text in Latin-1
+            // hardly ever passes for
+            .filter(|&encoding| encoding != Encoding::Windows1252 || str::from_utf8(bytes).is_err())
+            .and_then(|encoding| encoding.decode(bytes).map(|text| (text, encoding, false)))
+    };
+    let guessed = || {
+        guess_utf16(bytes)
+            .into_iter()
+            .chain(Some(Encoding::Utf8))
+            .filter_map(|encoding| encoding.decode(bytes).map(|text| (text, encoding, false)))
+            .next()
+    };
+    let (text, encoding, bom) = marked
+        .or_else(declared)
+        .or_else(guessed)
+        .unwrap_or_else(|| {
+                            let text = Encoding::Windows1252.decode(bytes).unwrap_or_default();
+                            (text, Encoding::Windows1252, false)
+                        });
+    let lines = text.matches('\n').count();
+    let crlf = lines > 0 && text.matches("\r\n").count() == lines;
+    let text = if crlf {
+        text.replace("\r\n", "\n")
+    } else {
+        text
+    };
+    (text,
+     Format {
+         encoding,
+         bom,
+         crlf,
+     })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check bytes are read as the text and format given, and written back the same
+    fn assert_round_trip(bytes: &[u8], charset: Option<&str>, text: &str, format: Format) {
+        let (decoded, found) = decode(bytes, charset);
+        assert_eq!(decoded, text);
+        assert_eq!(found, format);
+        assert_eq!(found.encode(&decoded).unwrap(), bytes);
+    }
+
+    #[test]
+    fn line_endings_are_kept() {
+        assert_round_trip(b"a\nb\n", None, "a\nb\n", Format::default());
+        assert_round_trip(b"a\r\nb\r\n",
+                          None,
+                          "a\nb\n",
+                          Format {
+                              crlf: true,
+                              ..Format::default()
+                          });
+        // Mixed line endings are left alone, rather than all changed to the first
+        assert_round_trip(b"a\r\nb\nc\r\n", None, "a\r\nb\nc\r\n", Format::default());
+        assert_round_trip(b"a\nb\r\n", None, "a\nb\r\n", Format::default());
+    }
+
+    #[test]
+    fn encodings_are_detected() {
+        assert_round_trip(b"\xEF\xBB\xBFcaf\xC3\xA9\r\n",
+                          None,
+                          "caf\u{E9}\n",
+                          Format {
+                              encoding: Encoding::Utf8,
+                              bom: true,
+                              crlf: true,
+                          });
+        assert_round_trip(b"\xFE\xFF\x00h\x00i",
+                          None,
+                          "hi",
+                          Format {
+                              encoding: Encoding::Utf16Be,
+                              bom: true,
+                              crlf: false,
+                          });
+        assert_round_trip(b"h\x00i\x00\n\x00",
+                          None,
+                          "hi\n",
+                          Format {
+                              encoding: Encoding::Utf16Le,
+                              ..Format::default()
+                          });
+        assert_round_trip(b"caf\xE9 \x80\x81",
+                          None,
+                          "caf\u{E9} \u{20AC}\u{81}",
+                          Format {
+                              encoding: Encoding::Windows1252,
+                              ..Format::default()
+                          });
+    }
+
+    #[test]
+    fn declared_charsets_are_used() {
+        assert_eq!(charset("text/markdown; charset=\"ISO-8859-1\""), Some("ISO-8859-1"));
+        assert_round_trip(b"\xA3",
+                          Some("latin1"),
+                          "\u{A3}",
+                          Format {
+                              encoding: Encoding::Windows1252,
+                              ..Format::default()
+                          });
+        // Text said to be in Latin-1 that is valid UTF-8 is taken to be UTF-8
+        assert_round_trip(b"\xC2\xA3", Some("latin1"), "\u{A3}", Format::default());
+    }
+
+    #[test]
+    fn unencodable_characters_are_found() {
+        assert_eq!(Encoding::Windows1252.encode("a\u{3B1}"), Err('\u{3B1}'));
+    }
+}
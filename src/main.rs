@@ -1,14 +1,27 @@
 extern crate gtk;
+extern crate cairo;
 extern crate gdk;
 extern crate gdk_pixbuf;
+extern crate gdk_pixbuf_sys as gdk_pixbuf_ffi;
+extern crate glib;
+extern crate glib_sys as glib_ffi;
 extern crate pango;
 extern crate pulldown_cmark;
 extern crate hyper_native_tls;
 extern crate hyper;
 extern crate webbrowser;
+extern crate base64;
+extern crate percent_encoding;
 
 mod app;
+mod auth;
+mod cache;
+mod document;
+mod encoding;
+mod network;
 mod source;
+#[cfg(test)]
+mod test_server;
 mod util;
 mod view;
 
@@ -0,0 +1,232 @@
+use hyper;
+use hyper::Url;
+use hyper::net::{HttpStream, NetworkConnector};
+
+use util;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// How long to wait for servers, how far to follow them and which proxies to reach them
+/// through, where all but the proxies can be changed in `~/.config/litepad/network`
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// How long to wait for a connection to a server, or forever if not set
+    pub connect_timeout: Option<Duration>,
+    /// How long to wait for a server to send or take anything, or forever if not set
+    pub read_timeout: Option<Duration>,
+    /// How many redirects to follow from a request before giving up
+    pub max_redirects: u32,
+    /// The proxy for `http:` URLs, from `http_proxy`
+    pub http_proxy: Option<String>,
+    /// The proxy for `https:` URLs, from `https_proxy`
+    pub https_proxy: Option<String>,
+    /// The hosts and domains reached without a proxy, from `no_proxy`
+    pub no_proxy: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            connect_timeout: Some(Duration::from_secs(10)),
+            read_timeout: Some(Duration::from_secs(30)),
+            max_redirects: 10,
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Read the settings, with a setting on each line like `read-timeout 60`, and the proxies
+    /// from the environment
+    ///
+    /// Timeouts are in seconds, where 0 means to wait forever. Settings left out or not
+    /// understood keep their defaults.
+    pub fn load() -> Settings {
+        let settings = Settings {
+            http_proxy: proxy_var("http_proxy"),
+            https_proxy: proxy_var("https_proxy"),
+            no_proxy: proxy_var("no_proxy"),
+            ..Settings::default()
+        };
+        match util::config_file("network").and_then(|path| File::open(path).ok()) {
+            Some(file) => settings.read(BufReader::new(file)),
+            None => settings,
+        }
+    }
+    /// Change the settings to the ones read from a file, in the format `load` reads
+    fn read<R: BufRead>(mut self, reader: R) -> Settings {
+        let timeout = |seconds| if seconds == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(seconds))
+        };
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next().and_then(|value| value.parse::<u64>().ok())) {
+                (Some("connect-timeout"), Some(seconds)) => self.connect_timeout = timeout(seconds),
+                (Some("read-timeout"), Some(seconds)) => self.read_timeout = timeout(seconds),
+                (Some("max-redirects"), Some(count)) => self.max_redirects = count as u32,
+                _ => (),
+            }
+        }
+        self
+    }
+    /// Find the host and port of the proxy to reach a URL through, the one for its scheme,
+    /// unless its host is in `no_proxy`
+    ///
+    /// Proxies are always spoken to over plain HTTP, and ones that need a login aren't
+    /// supported.
+    pub fn proxy(&self, url: &Url) -> Option<(String, u16)> {
+        let host = match url.host_str() {
+            Some(host) => host.trim_left_matches('[').trim_right_matches(']'),
+            None => return None,
+        };
+        // A proxy elsewhere would reach itself rather than this machine
+        let loopback = host.eq_ignore_ascii_case("localhost") ||
+                       host.parse::<IpAddr>().map_or(false, |ip| ip.is_loopback());
+        if loopback ||
+           self.no_proxy.as_ref().map_or(false, |no_proxy| bypasses_proxy(host, no_proxy)) {
+            return None;
+        }
+        let proxy = match url.scheme() {
+            "http" => self.http_proxy.as_ref(),
+            "https" => self.https_proxy.as_ref(),
+            _ => None,
+        };
+        proxy
+            .and_then(|proxy| if proxy.contains("://") {
+                          Url::parse(proxy).ok()
+                      } else {
+                          Url::parse(&format!("http://{}", proxy)).ok()
+                      })
+            .and_then(|proxy| {
+                          let port = proxy.port_or_known_default().unwrap_or(80);
+                          proxy.host_str().map(|host| (host.to_owned(), port))
+                      })
+    }
+}
+
+/// Read an environment variable as it is usually spelt for proxies, in lower case, or failing
+/// that in upper case
+fn proxy_var(name: &str) -> Option<String> {
+    env::var(name)
+        .or_else(|_| env::var(name.to_uppercase()))
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+/// Check if a host is one of the hosts or domains in a `no_proxy` list, which are separated by
+/// commas, or if the list is `*`
+fn bypasses_proxy(host: &str, no_proxy: &str) -> bool {
+    no_proxy
+        .split(',')
+        .map(|entry| entry.trim())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            // Ports are ignored, as few proxies are only for some of a host's ports. IPv6
+            // addresses have colons of their own, so need brackets to be given a port.
+            let entry = if entry.starts_with('[') {
+                entry[1..].split(']').next().unwrap_or_default()
+            } else if entry.matches(':').count() == 1 {
+                entry.split(':').next().unwrap_or_default()
+            } else {
+                entry
+            };
+            let entry = entry.trim_left_matches('.');
+            entry == "*" || host.eq_ignore_ascii_case(entry) ||
+            host.to_lowercase().ends_with(&format!(".{}", entry.to_lowercase()))
+        })
+}
+
+/// Connects to servers over TCP like hyper's own connector, but gives up on ones that take too
+/// long to answer
+pub struct Connector {
+    pub timeout: Option<Duration>,
+}
+
+impl NetworkConnector for Connector {
+    type Stream = HttpStream;
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<HttpStream> {
+        if scheme != "http" {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid scheme for Http")
+                           .into());
+        }
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Ok(HttpStream(TcpStream::connect((host, port))?)),
+        };
+        // Each address the host has is tried in turn, like `TcpStream::connect` does
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound,
+                                          format!("Couldn't find the address of {}", host));
+        for addr in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(HttpStream(stream)),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_in_no_proxy_bypass_the_proxy() {
+        let no_proxy = "intranet, .example.com,Docs.Local:8080 ,[::1]:631,fe80::1";
+        assert!(bypasses_proxy("intranet", no_proxy));
+        assert!(!bypasses_proxy("intranet.org", no_proxy));
+        assert!(bypasses_proxy("example.com", no_proxy));
+        assert!(bypasses_proxy("www.EXAMPLE.com", no_proxy));
+        assert!(!bypasses_proxy("notexample.com", no_proxy));
+        assert!(bypasses_proxy("docs.local", no_proxy));
+        assert!(bypasses_proxy("::1", no_proxy));
+        assert!(bypasses_proxy("fe80::1", no_proxy));
+        assert!(!bypasses_proxy("fe80::2", no_proxy));
+        assert!(bypasses_proxy("anywhere.org", "*"));
+        assert!(!bypasses_proxy("anywhere.org", ""));
+    }
+
+    #[test]
+    fn urls_go_through_the_proxy_for_their_scheme() {
+        let settings = Settings {
+            http_proxy: Some("proxy.local:3128".to_owned()),
+            https_proxy: Some("http://secure.local".to_owned()),
+            no_proxy: Some(".example.com".to_owned()),
+            ..Settings::default()
+        };
+        let proxy = |url| settings.proxy(&Url::parse(url).unwrap());
+        assert_eq!(proxy("http://site.org/a"), Some(("proxy.local".to_owned(), 3128)));
+        assert_eq!(proxy("https://site.org/a"), Some(("secure.local".to_owned(), 80)));
+        assert_eq!(proxy("http://www.example.com/a"), None);
+        assert_eq!(proxy("http://localhost:8080/a"), None);
+        assert_eq!(proxy("http://127.0.0.1/a"), None);
+        assert_eq!(proxy("http://[::1]/a"), None);
+        assert_eq!(Settings::default().proxy(&Url::parse("http://site.org").unwrap()), None);
+    }
+
+    #[test]
+    fn settings_are_read_from_each_line() {
+        let file = "connect-timeout 5\nread-timeout 0\nmax-redirects 3\n\
+                    unknown 1\nread-timeout soon\n";
+        let settings = Settings::default().read(file.as_bytes());
+        assert_eq!(settings.connect_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(settings.read_timeout, None);
+        assert_eq!(settings.max_redirects, 3);
+        let settings = Settings::default().read(&b""[..]);
+        assert_eq!(settings.connect_timeout, Some(Duration::from_secs(10)));
+        assert_eq!(settings.read_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(settings.max_redirects, 10);
+    }
+}
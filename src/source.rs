@@ -1,9 +1,19 @@
+use std::env;
+use std::error;
 use std::fmt;
-use std::fs::File;
-use std::io::{BufWriter, BufRead, BufReader, Write};
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use auth::{self, Challenge, Credentials};
+use encoding::{self, Encoding, Format};
 use util;
+use hyper;
 use hyper::Url;
+use hyper::client::Response;
+use hyper::header::Headers;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use hyper_native_tls::native_tls;
 
 /// A source from which documents can be loaded
 #[derive(Clone, Eq, PartialEq)]
@@ -13,39 +23,400 @@ pub enum Source {
     Url(Url),
 }
 
-impl Source {
-    pub fn load(&self) -> String {
-        let mut text = String::new();
-        if let Some(mut reader) = self.reader() {
-            reader.read_to_string(&mut text).unwrap();
+/// What is known about where a document is kept, to save it back without overwriting changes
+/// made there in the meantime
+#[derive(Clone, Debug, Default)]
+pub struct Remote {
+    /// Whether loading the document failed, so saving would replace it with nothing
+    pub unloaded: bool,
+    /// The entity tag the server gave the version last loaded or saved
+    pub etag: Option<String>,
+    /// The token of the WebDAV lock held on the document, if it has been locked
+    pub lock: Option<String>,
+    /// The login the server accepted, if it asked for one
+    pub credentials: Option<Credentials>,
+}
+
+/// A document as loaded from its source
+pub struct Loaded {
+    pub text: String,
+    pub remote: Remote,
+    /// Where the document was loaded from in the end, if a server redirected somewhere else
+    pub moved: Option<Url>,
+    /// How the document was written, to write it back the same way
+    pub format: Format,
+}
+
+/// How long WebDAV locks last for in seconds, unless renewed or released first
+const LOCK_TIMEOUT: u32 = 3600;
+/// The body of a request for an exclusive WebDAV write lock
+const LOCK_INFO: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:lockinfo xmlns:D="DAV:">
+  <D:lockscope><D:exclusive/></D:lockscope>
+  <D:locktype><D:write/></D:locktype>
+</D:lockinfo>
+"#;
+
+/// Read a header as it was sent
+fn raw_header(headers: &Headers, name: &str) -> Option<String> {
+    headers
+        .get_raw(name)
+        .and_then(|values| values.first())
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+/// Turn an answer from the server that isn't a success into an error
+fn status_error(res: &Response) -> Error {
+    match res.status {
+        StatusCode::Unauthorized => {
+            let challenge = raw_header(&res.headers, "WWW-Authenticate").unwrap_or_default();
+            Error::Unauthorized(Challenge::parse(&challenge))
         }
-        text
+        status => Error::Status(status),
     }
-    pub fn writer(&self) -> Option<Box<Write>> {
+}
+
+/// What can go wrong loading or saving a document
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing failed, or the connection to the server did
+    Io(io::Error),
+    /// The server answered with an error, like `404 Not Found`
+    Status(StatusCode),
+    /// The server needs a login, or turned down the one given
+    Unauthorized(Challenge),
+    /// A secure connection to the server couldn't be made
+    Tls(Box<error::Error + Send + Sync>),
+    /// The server took too long to answer
+    TimedOut,
+    /// The server kept redirecting for longer than redirects are followed
+    Redirects,
+    /// The document was changed on the server since it was loaded, so saving would overwrite
+    /// those changes
+    Conflict,
+    /// The server didn't answer with valid HTTP
+    Http(hyper::Error),
+    /// The document has a character that can't be written in its encoding
+    Unencodable(char, Encoding),
+    /// The document couldn't be loaded, so saving would replace it with nothing
+    NotLoaded,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Source::File(ref path) => {
-                let path = path.as_path();
-                let file = File::create(path).unwrap();
-                Some(Box::new(BufWriter::new(file)))
+            Error::Io(ref err) => err.fmt(f),
+            Error::Status(status) => write!(f, "The server answered with {}", status),
+            Error::Unauthorized(Challenge { realm: Some(ref realm), .. }) => {
+                write!(f, "The server needs a login for \"{}\"", realm)
+            }
+            Error::Unauthorized(_) => f.write_str("The server needs a login"),
+            Error::Tls(ref err) => write!(f, "Couldn't make a secure connection: {}", err),
+            Error::TimedOut => f.write_str("The server took too long to answer"),
+            Error::Redirects => f.write_str("The server redirected too many times"),
+            Error::Conflict => {
+                f.write_str("The document was changed on the server since it was loaded")
+            }
+            Error::Http(ref err) => err.fmt(f),
+            Error::Unencodable(c, encoding) => {
+                write!(f, "'{}' can't be written in {}", c, encoding)
+            }
+            Error::NotLoaded => {
+                f.write_str("The document couldn't be loaded, so saving would replace it with \
+                             nothing")
             }
-            _ => None,
         }
     }
-    pub fn reader(&self) -> Option<Box<BufRead>> {
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(ref err) => err.description(),
+            Error::Status(_) => "The server answered with an error",
+            Error::Unauthorized(_) => "The server needs a login",
+            Error::Tls(ref err) => err.description(),
+            Error::TimedOut => "The server took too long to answer",
+            Error::Redirects => "The server redirected too many times",
+            Error::Conflict => "The document was changed on the server",
+            Error::Http(ref err) => err.description(),
+            Error::Unencodable(_, _) => "A character can't be written in the document's encoding",
+            Error::NotLoaded => "The document couldn't be loaded",
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Error {
+        match err {
+            // Timeouts show up as reads that would block on some platforms
+            hyper::Error::Io(ref err) if err.kind() == io::ErrorKind::TimedOut ||
+                                         err.kind() == io::ErrorKind::WouldBlock => {
+                Error::TimedOut
+            }
+            hyper::Error::Io(err) => Error::Io(err),
+            hyper::Error::Ssl(err) => Error::Tls(err),
+            err => Error::Http(err),
+        }
+    }
+}
+
+impl From<native_tls::Error> for Error {
+    fn from(err: native_tls::Error) -> Error {
+        Error::Tls(Box::new(err))
+    }
+}
+
+impl Source {
+    /// Read the document, which is empty if it isn't anywhere yet
+    ///
+    /// Documents on servers are asked for with the login given, or else the one kept for the
+    /// server, if any. Documents in any encoding are read, as told by the `charset` servers
+    /// give or else by what they look like.
+    pub fn load(&self, credentials: Option<Credentials>) -> Result<Loaded, Error> {
+        let mut bytes = Vec::new();
+        let mut remote = Remote::default();
+        let mut moved = None;
+        let mut charset = None;
         match *self {
             Source::File(ref path) => {
-                let path = path.as_path();
-                let file = File::open(path).unwrap();
-                Some(Box::new(BufReader::new(file)))
+                File::open(path)?.read_to_end(&mut bytes)?;
             }
             Source::Url(ref url) => {
-                let client = util::make_client();
-                let res = client.get(url.clone()).send().unwrap();
-                Some(Box::new(BufReader::new(res)))
+                let credentials =
+                    credentials.or_else(|| auth::site(url).and_then(|site| auth::lookup(&site)));
+                let mut res = util::send(Method::Get,
+                                         url,
+                                         &Headers::new(),
+                                         None,
+                                         credentials.as_ref())?;
+                if !res.status.is_success() {
+                    return Err(status_error(&res));
+                }
+                if res.url != *url {
+                    moved = Some(res.url.clone());
+                }
+                charset = raw_header(&res.headers, "Content-Type")
+                    .and_then(|content_type| encoding::charset(&content_type).map(str::to_owned));
+                remote.etag = raw_header(&res.headers, "ETag");
+                remote.credentials = credentials;
+                res.read_to_end(&mut bytes)?;
+            }
+            Source::Unknown => (),
+        }
+        let (text, format) = encoding::decode(&bytes, charset.as_ref().map(String::as_str));
+        Ok(Loaded {
+               text,
+               remote,
+               moved,
+               format,
+           })
+    }
+    /// Check if the document can be saved back to where it came from
+    pub fn can_save(&self) -> bool {
+        match *self {
+            Source::File(_) | Source::Url(_) => true,
+            Source::Unknown => false,
+        }
+    }
+    /// Save the document, keeping what it replaces as a backup if asked to
+    ///
+    /// Documents on servers are `PUT` back, but only if they haven't changed on the server since
+    /// they were loaded or last saved, as told by `remote`, which is updated once saved.
+    /// Sources that can't be saved to are left alone.
+    pub fn save(&self, bytes: &[u8], backup: bool, remote: &mut Remote) -> Result<(), Error> {
+        if remote.unloaded {
+            return Err(Error::NotLoaded);
+        }
+        match *self {
+            Source::File(ref path) => Ok(save_file(path, bytes, backup)?),
+            Source::Url(ref url) => {
+                let mut headers = Headers::new();
+                if let Some(ref etag) = remote.etag {
+                    headers.set_raw("If-Match", vec![etag.clone().into_bytes()]);
+                }
+                if let Some(ref token) = remote.lock {
+                    headers.set_raw("If", vec![format!("(<{}>)", token).into_bytes()]);
+                }
+                let res = util::send(Method::Put,
+                                     url,
+                                     &headers,
+                                     Some(bytes),
+                                     remote.credentials.as_ref())?;
+                match res.status {
+                    StatusCode::PreconditionFailed => Err(Error::Conflict),
+                    status if status.is_success() => {
+                        // Without a new tag, the next save can't tell if it would overwrite
+                        // someone else's changes, so goes ahead regardless
+                        remote.etag = raw_header(&res.headers, "ETag");
+                        Ok(())
+                    }
+                    _ => Err(status_error(&res)),
+                }
+            }
+            Source::Unknown => Ok(()),
+        }
+    }
+    /// Take a WebDAV lock on a document on a server, so no one else can change it until it is
+    /// unlocked, returning the token for the lock
+    pub fn lock(&self, credentials: Option<&Credentials>) -> Result<Option<String>, Error> {
+        let url = match *self {
+            Source::Url(ref url) => url,
+            _ => return Ok(None),
+        };
+        let mut headers = Headers::new();
+        headers.set_raw("Timeout", vec![format!("Second-{}", LOCK_TIMEOUT).into_bytes()]);
+        headers.set_raw("Content-Type", vec![b"application/xml; charset=utf-8".to_vec()]);
+        let res = util::send(Method::Extension("LOCK".to_owned()),
+                             url,
+                             &headers,
+                             Some(LOCK_INFO.as_bytes()),
+                             credentials)?;
+        if !res.status.is_success() {
+            return Err(status_error(&res));
+        }
+        Ok(raw_header(&res.headers, "Lock-Token").map(|token| {
+                                                         token
+                                                             .trim()
+                                                             .trim_left_matches('<')
+                                                             .trim_right_matches('>')
+                                                             .to_owned()
+                                                     }))
+    }
+    /// Release a WebDAV lock taken by `lock`
+    pub fn unlock(&self, token: &str, credentials: Option<&Credentials>) -> Result<(), Error> {
+        let url = match *self {
+            Source::Url(ref url) => url,
+            _ => return Ok(()),
+        };
+        let mut headers = Headers::new();
+        headers.set_raw("Lock-Token", vec![format!("<{}>", token).into_bytes()]);
+        let res = util::send(Method::Extension("UNLOCK".to_owned()),
+                             url,
+                             &headers,
+                             None,
+                             credentials)?;
+        if res.status.is_success() {
+            Ok(())
+        } else {
+            Err(status_error(&res))
+        }
+    }
+    /// The URL that relative references in the document are resolved against
+    ///
+    /// Documents not saved anywhere yet use the current directory.
+    pub fn base(&self) -> Option<Url> {
+        match *self {
+            Source::File(ref path) => {
+                env::current_dir()
+                    .ok()
+                    .and_then(|dir| Url::from_file_path(dir.join(path)).ok())
+            }
+            Source::Url(ref url) => Some(url.clone()),
+            Source::Unknown => {
+                env::current_dir()
+                    .ok()
+                    .and_then(|dir| Url::from_directory_path(dir).ok())
+            }
+        }
+    }
+
+    /// Resolve a reference to an image or link in the document, which may be a URL, an absolute
+    /// path, or a path relative to the document
+    pub fn resolve(&self, reference: &str) -> Option<Url> {
+        if let Ok(url) = Url::parse(reference) {
+            return Some(url);
+        }
+        // On the web, an absolute path is relative to the site's root
+        let local = match *self {
+            Source::File(_) | Source::Unknown => true,
+            Source::Url(_) => false,
+        };
+        if local && Path::new(reference).is_absolute() {
+            return Url::from_file_path(reference).ok();
+        }
+        self.base().and_then(|base| base.join(reference).ok())
+    }
+}
+
+/// Find the file a path leads to, following any symbolic links, so that saving replaces the
+/// file rather than the link
+fn resolve_links(path: &Path) -> PathBuf {
+    match fs::canonicalize(path) {
+        Ok(target) => target,
+        // A link to a file that doesn't exist yet is saved to where it points
+        Err(_) => {
+            match fs::read_link(path) {
+                Ok(link) => path.parent().unwrap_or(Path::new("")).join(link),
+                Err(_) => path.to_owned(),
+            }
+        }
+    }
+}
+
+/// Save a file without ever leaving it half written, by writing to a temporary file next to it
+/// then moving that over it, keeping the file it replaces as `file~` if asked to
+fn save_file(path: &Path, bytes: &[u8], backup: bool) -> io::Result<()> {
+    let target = resolve_links(path);
+    let dir = target.parent().unwrap_or(Path::new("")).to_owned();
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let existing = fs::metadata(&target).ok();
+    let (temp, mut file) = temp_file(&dir, &name)?;
+    let written = file.write_all(bytes)
+        .and_then(|_| file.sync_all())
+        .and_then(|_| match existing {
+                      Some(ref metadata) => fs::set_permissions(&temp, metadata.permissions()),
+                      None => Ok(()),
+                  })
+        .and_then(|_| if backup && existing.is_some() {
+                      backup_file(&target, &dir.join(format!("{}~", name)))
+                  } else {
+                      Ok(())
+                  })
+        .and_then(|_| fs::rename(&temp, &target));
+    if written.is_err() {
+        fs::remove_file(&temp).ok();
+        return written;
+    }
+    // Make sure the rename itself survives a crash, where directories can be synced
+    if let Ok(dir) = File::open(&dir) {
+        dir.sync_all().ok();
+    }
+    Ok(())
+}
+
+/// Create a new hidden file to save to in a directory, named after the file it will replace
+fn temp_file(dir: &Path, name: &str) -> io::Result<(PathBuf, File)> {
+    let mut attempt = 0;
+    loop {
+        let path = dir.join(format!(".{}.{}.tmp", name, attempt));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => {
+                attempt += 1
             }
-            _ => None,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Keep a copy of a file about to be replaced, linking to it where possible so it isn't copied
+fn backup_file(target: &Path, backup: &Path) -> io::Result<()> {
+    if let Err(err) = fs::remove_file(backup) {
+        if err.kind() != io::ErrorKind::NotFound {
+            return Err(err);
         }
     }
+    fs::hard_link(target, backup).or_else(|_| fs::copy(target, backup).map(|_| ()))
 }
 
 impl fmt::Display for Source {
@@ -57,3 +428,226 @@ impl fmt::Display for Source {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server;
+
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    /// Make an empty directory for a test to keep files in
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("litepad-source-{}", name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// List the names of the files in a directory, in order
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|file| file.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn read(path: &Path) -> String {
+        let mut text = String::new();
+        File::open(path).unwrap().read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn saving_replaces_the_file_keeping_its_permissions() {
+        let dir = test_dir("replace");
+        let path = dir.join("a.md");
+        File::create(&path).unwrap().write_all(b"old\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        save_file(&path, b"new\n", false).unwrap();
+        assert_eq!(read(&path), "new\n");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        assert_eq!(files(&dir), ["a.md"]);
+        // New files are made too
+        save_file(&dir.join("b.md"), b"b\n", false).unwrap();
+        assert_eq!(read(&dir.join("b.md")), "b\n");
+    }
+
+    #[test]
+    fn backups_are_only_kept_when_asked_for() {
+        let dir = test_dir("backup");
+        let path = dir.join("a.md");
+        save_file(&path, b"one\n", true).unwrap();
+        assert_eq!(files(&dir), ["a.md"]);
+        save_file(&path, b"two\n", false).unwrap();
+        assert_eq!(files(&dir), ["a.md"]);
+        save_file(&path, b"three\n", true).unwrap();
+        assert_eq!(files(&dir), ["a.md", "a.md~"]);
+        assert_eq!(read(&dir.join("a.md~")), "two\n");
+        save_file(&path, b"four\n", true).unwrap();
+        assert_eq!(read(&dir.join("a.md~")), "three\n");
+        assert_eq!(read(&path), "four\n");
+    }
+
+    #[test]
+    fn saving_through_a_link_replaces_what_it_links_to() {
+        let dir = test_dir("link");
+        fs::create_dir(dir.join("real")).unwrap();
+        File::create(dir.join("real").join("a.md")).unwrap();
+        symlink("real/a.md", dir.join("link.md")).unwrap();
+        save_file(&dir.join("link.md"), b"new\n", false).unwrap();
+        assert_eq!(read(&dir.join("real").join("a.md")), "new\n");
+        assert_eq!(fs::read_link(dir.join("link.md")).unwrap(), Path::new("real/a.md"));
+        assert_eq!(files(&dir), ["link.md", "real"]);
+        assert_eq!(files(&dir.join("real")), ["a.md"]);
+        // Links to files that don't exist yet make them
+        symlink("real/b.md", dir.join("new.md")).unwrap();
+        save_file(&dir.join("new.md"), b"b\n", false).unwrap();
+        assert_eq!(read(&dir.join("real").join("b.md")), "b\n");
+        assert!(fs::symlink_metadata(dir.join("new.md")).unwrap().file_type().is_symlink());
+    }
+
+    #[test]
+    fn failed_saves_leave_nothing_behind() {
+        let dir = test_dir("failed");
+        // A file can't be moved over a directory
+        fs::create_dir(dir.join("a.md")).unwrap();
+        assert!(save_file(&dir.join("a.md"), b"new\n", false).is_err());
+        assert_eq!(files(&dir), ["a.md"]);
+        assert!(dir.join("a.md").is_dir());
+    }
+
+    #[test]
+    fn temporary_files_never_clash() {
+        let dir = test_dir("temp");
+        let (first, _) = temp_file(&dir, "a.md").unwrap();
+        let (second, _) = temp_file(&dir, "a.md").unwrap();
+        assert_eq!(first, dir.join(".a.md.0.tmp"));
+        assert_eq!(second, dir.join(".a.md.1.tmp"));
+    }
+
+    #[test]
+    fn saves_only_replace_the_version_loaded() {
+        let (url, requests) = test_server::serve(&["HTTP/1.1 200 OK\r\nETag: \"v1\"\r\n\
+                                                    Content-Length: 4\r\n\r\nold\n",
+                                                   "HTTP/1.1 204 No Content\r\nETag: \"v2\"\r\n\
+                                                    Content-Length: 0\r\n\r\n",
+                                                   "HTTP/1.1 412 Precondition Failed\r\n\
+                                                    Content-Length: 0\r\n\r\n"]);
+        let source = Source::Url(url);
+        let mut remote = source.load(None).unwrap().remote;
+        assert_eq!(remote.etag, Some("\"v1\"".to_owned()));
+        requests.recv().unwrap();
+        source.save(b"new\n", false, &mut remote).unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("PUT / HTTP/1.1\r\n"));
+        assert!(request.contains("If-Match: \"v1\"\r\n"));
+        assert!(request.ends_with("\r\n\r\nnew\n"));
+        assert_eq!(remote.etag, Some("\"v2\"".to_owned()));
+        // Someone else has saved over it since
+        match source.save(b"newer\n", false, &mut remote) {
+            Err(Error::Conflict) => (),
+            saved => panic!("Saved over changes on the server: {:?}", saved.err()),
+        }
+        assert!(requests.recv().unwrap().contains("If-Match: \"v2\"\r\n"));
+        assert_eq!(remote.etag, Some("\"v2\"".to_owned()));
+    }
+
+    #[test]
+    fn locks_are_held_while_saving() {
+        let (url, requests) = test_server::serve(&["HTTP/1.1 200 OK\r\n\
+                                                    Lock-Token: <opaquelocktoken:abc>\r\n\
+                                                    Content-Length: 0\r\n\r\n",
+                                                   "HTTP/1.1 204 No Content\r\n\
+                                                    Content-Length: 0\r\n\r\n",
+                                                   "HTTP/1.1 204 No Content\r\n\
+                                                    Content-Length: 0\r\n\r\n",
+                                                   "HTTP/1.1 409 Conflict\r\n\
+                                                    Content-Length: 0\r\n\r\n"]);
+        let source = Source::Url(url);
+        let token = source.lock(None).unwrap();
+        assert_eq!(token, Some("opaquelocktoken:abc".to_owned()));
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("LOCK / HTTP/1.1\r\n"));
+        assert!(request.contains("Timeout: Second-3600\r\n"));
+        assert!(request.ends_with(LOCK_INFO));
+        let mut remote = Remote {
+            lock: token,
+            ..Remote::default()
+        };
+        source.save(b"text\n", false, &mut remote).unwrap();
+        assert!(requests.recv().unwrap().contains("If: (<opaquelocktoken:abc>)\r\n"));
+        source.unlock("opaquelocktoken:abc", None).unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("UNLOCK / HTTP/1.1\r\n"));
+        assert!(request.contains("Lock-Token: <opaquelocktoken:abc>\r\n"));
+        assert!(source.unlock("opaquelocktoken:abc", None).is_err());
+    }
+
+    #[test]
+    fn references_resolve_against_the_document() {
+        let url = |url: &str| Some(Url::parse(url).unwrap());
+        let file = Source::File(PathBuf::from("/docs/guide/intro.md"));
+        assert_eq!(file.base(), url("file:///docs/guide/intro.md"));
+        assert_eq!(file.resolve("img/a.png"), url("file:///docs/guide/img/a.png"));
+        assert_eq!(file.resolve("../b.md"), url("file:///docs/b.md"));
+        assert_eq!(file.resolve("/etc/c.png"), url("file:///etc/c.png"));
+        assert_eq!(file.resolve("https://example.com/d"), url("https://example.com/d"));
+
+        let web = Source::Url(Url::parse("https://example.com/docs/intro.md?raw=1").unwrap());
+        assert_eq!(web.base(), url("https://example.com/docs/intro.md?raw=1"));
+        assert_eq!(web.resolve("img/a.png"), url("https://example.com/docs/img/a.png"));
+        assert_eq!(web.resolve("../b.md"), url("https://example.com/b.md"));
+        assert_eq!(web.resolve("/etc/c.png"), url("https://example.com/etc/c.png"));
+        assert_eq!(web.resolve("//cdn.example.org/e.png"), url("https://cdn.example.org/e.png"));
+        assert_eq!(web.resolve("mailto:me@example.com"), url("mailto:me@example.com"));
+
+        let dir = env::current_dir().unwrap();
+        let unknown = Source::Unknown;
+        assert_eq!(unknown.base(), Url::from_directory_path(&dir).ok());
+        assert_eq!(unknown.resolve("a.png"), Url::from_file_path(dir.join("a.png")).ok());
+        assert_eq!(unknown.resolve("/etc/c.png"), url("file:///etc/c.png"));
+    }
+
+    #[test]
+    fn failures_to_load_are_told_apart() {
+        let (url, _requests) = test_server::serve(&["HTTP/1.1 404 Not Found\r\n\
+                                                     Content-Length: 0\r\n\r\n",
+                                                    "HTTP/1.1 401 Unauthorized\r\n\
+                                                     WWW-Authenticate: Basic realm=\"docs\"\r\n\
+                                                     Content-Length: 0\r\n\r\n"]);
+        let source = Source::Url(url);
+        match source.load(None) {
+            Err(Error::Status(StatusCode::NotFound)) => (),
+            loaded => panic!("Expected 404 Not Found, got {:?}", loaded.err()),
+        }
+        match source.load(None) {
+            Err(Error::Unauthorized(Challenge { realm: Some(ref realm), .. })) => {
+                assert_eq!(realm, "docs")
+            }
+            loaded => panic!("Expected a login to be asked for, got {:?}", loaded.err()),
+        }
+        let missing = test_dir("missing").join("a.md");
+        match Source::File(missing).load(None) {
+            Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => (),
+            loaded => panic!("Expected the file not to be found, got {:?}", loaded.err()),
+        }
+    }
+
+    #[test]
+    fn documents_that_failed_to_load_are_not_saved_over() {
+        let path = test_dir("unloaded").join("a.md");
+        File::create(&path).unwrap().write_all(b"kept\n").unwrap();
+        let mut remote = Remote {
+            unloaded: true,
+            ..Remote::default()
+        };
+        match Source::File(path.clone()).save(b"", false, &mut remote) {
+            Err(Error::NotLoaded) => (),
+            saved => panic!("Saved over a document that wasn't loaded: {:?}", saved.err()),
+        }
+        assert_eq!(read(&path), "kept\n");
+    }
+}
@@ -0,0 +1,55 @@
+use hyper::Url;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// Start a server on a free local port that gives the responses given to the requests it gets,
+/// in turn, and passes on the requests
+pub fn serve(responses: &[&str]) -> (Url, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    let responses: Vec<String> = responses.iter().map(|&response| response.to_owned()).collect();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || for (stream, response) in listener.incoming().zip(responses) {
+                      let mut stream = stream.unwrap();
+                      sender.send(read_request(&mut stream)).ok();
+                      stream.write_all(response.as_bytes()).ok();
+                  });
+    (url, receiver)
+}
+
+/// Read a whole request, going by its `Content-Length` for how long its body is
+fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let read = stream.read(&mut buf).unwrap_or(0);
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+        let text = String::from_utf8_lossy(&request).into_owned();
+        if let Some(end) = text.find("\r\n\r\n") {
+            let length = text[..end]
+                .lines()
+                .filter_map(|line| {
+                    let mut parts = line.splitn(2, ':');
+                    let name = parts.next().unwrap_or("");
+                    match parts.next() {
+                        Some(value) if name.eq_ignore_ascii_case("content-length") => {
+                            value.trim().parse().ok()
+                        }
+                        _ => None,
+                    }
+                })
+                .next()
+                .unwrap_or(0);
+            if request.len() >= end + 4 + length {
+                return text;
+            }
+        }
+    }
+    String::from_utf8_lossy(&request).into_owned()
+}
@@ -1,48 +1,369 @@
 use hyper::net::HttpsConnector;
 use hyper::Client;
+use hyper::client::{ProxyConfig, RedirectPolicy, Response};
+use hyper::header::{Headers, Location};
+use hyper::method::Method;
+use hyper::status::StatusCode;
 use hyper_native_tls::NativeTlsClient;
+use hyper_native_tls::native_tls::{Identity, TlsConnector};
 
-use gdk_pixbuf::{Pixbuf, PixbufLoader, InterpType};
+use gdk_pixbuf::{InterpType, Pixbuf, PixbufAnimation, PixbufAnimationIter, PixbufLoader};
+use gdk_pixbuf::prelude::*;
+use gdk_pixbuf_ffi;
 
+use glib;
+use glib::translate::ToGlibPtr;
+use glib_ffi;
 
 use hyper::Url;
 
+use auth::{self, Credentials};
+use cache;
+use network::{Connector, Settings};
+use source::Error;
 
-use std::io::{BufReader, Read};
+use base64;
+use percent_encoding::percent_decode;
 
-/// Make a HTTPS-compatible client
-pub fn make_client() -> Client {
-    let ssl = NativeTlsClient::new().ok().expect("Failed to make TLS client");
-    let connector = HttpsConnector::new(ssl);
-    Client::with_connector(connector)
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+
+/// Make a HTTPS-compatible client for requests to `url`, which goes through any proxy set for
+/// it, gives up on servers that take too long, and shows the client certificate in
+/// `credentials` to the server if they are for one
+///
+/// The client doesn't follow redirects, as `send` does that itself.
+pub fn make_client(url: &Url,
+                   credentials: Option<&Credentials>,
+                   settings: &Settings)
+                   -> Result<Client, Error> {
+    let ssl = match credentials {
+        Some(&Credentials::Certificate {
+                   ref path,
+                   ref password,
+               }) => {
+            let mut der = Vec::new();
+            File::open(path)?.read_to_end(&mut der)?;
+            let identity = Identity::from_pkcs12(&der, password)?;
+            NativeTlsClient::from(TlsConnector::builder().identity(identity).build()?)
+        }
+        _ => NativeTlsClient::new()?,
+    };
+    let connector = Connector { timeout: settings.connect_timeout };
+    let mut client = match settings.proxy(url) {
+        Some((host, port)) => {
+            Client::with_proxy_config(ProxyConfig::new("http", host, port, connector, ssl))
+        }
+        None => Client::with_connector(HttpsConnector::with_connector(ssl, connector)),
+    };
+    client.set_read_timeout(settings.read_timeout);
+    client.set_write_timeout(settings.read_timeout);
+    client.set_redirect_policy(RedirectPolicy::FollowNone);
+    Ok(client)
+}
+
+/// Send a request, following redirects as far as the network settings allow, and return the
+/// answer from wherever it ended up, as told by its `url`
+///
+/// The login in `credentials` is only sent on to the server first asked, and never over a
+/// connection less secure than the first, so it doesn't leak to wherever that redirects.
+pub fn send(method: Method,
+            url: &Url,
+            headers: &Headers,
+            body: Option<&[u8]>,
+            credentials: Option<&Credentials>)
+            -> Result<Response, Error> {
+    send_with(&Settings::load(), method, url, headers, body, credentials)
+}
+
+/// Send a request like `send`, with the network settings given
+pub fn send_with(settings: &Settings,
+                 method: Method,
+                 url: &Url,
+                 headers: &Headers,
+                 body: Option<&[u8]>,
+                 credentials: Option<&Credentials>)
+                 -> Result<Response, Error> {
+    let site = auth::site(url);
+    let secure = url.scheme() == "https";
+    let mut method = method;
+    let mut url = url.clone();
+    let mut body = body;
+    for _ in 0..settings.max_redirects + 1 {
+        let credentials = if auth::site(&url) == site && (url.scheme() == "https" || !secure) {
+            credentials
+        } else {
+            None
+        };
+        let mut headers = headers.clone();
+        if let Some(credentials) = credentials {
+            credentials.authorize(&mut headers);
+        }
+        let client = make_client(&url, credentials, settings)?;
+        let request = client.request(method.clone(), url.clone()).headers(headers);
+        let res = match body {
+            Some(body) => request.body(body).send()?,
+            None => request.send()?,
+        };
+        let location = match res.headers.get::<Location>() {
+            Some(&Location(ref location)) if res.status.is_redirection() => {
+                url.join(location).ok()
+            }
+            _ => None,
+        };
+        match location {
+            Some(location) => {
+                // Only `303 See Other` asks for something else rather than the same again
+                if res.status == StatusCode::SeeOther {
+                    method = Method::Get;
+                    body = None;
+                }
+                url = location;
+            }
+            None => return Ok(res),
+        }
+    }
+    Err(Error::Redirects)
+}
+
+/// Find a file in the directory Litepad is configured in, under the XDG config directory
+pub fn config_file(name: &str) -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::home_dir().map(|home| home.join(".config")))
+        .map(|config| config.join("litepad").join(name))
 }
 
-/// Load images from urls, resizing to a certain width
-pub fn load_pixbufs(urls: &[Url], max_width: i32) -> Vec<Option<Pixbuf>> {
-    let client = make_client();
+/// Decode the data held in a `data:` URL
+fn decode_data_url(url: &Url) -> Option<Vec<u8>> {
+    let data = &url.as_str()["data:".len()..];
+    data.find(',').and_then(|comma| {
+        let mut payload: Vec<u8> = percent_decode(data[comma + 1..].as_bytes()).collect();
+        if data[..comma].ends_with(";base64") {
+            payload.retain(|byte| !(*byte as char).is_whitespace());
+            base64::decode(&payload).ok()
+        } else {
+            Some(payload)
+        }
+    })
+}
+
+/// Fetch the contents of a URL, from disk for `file:` URLs and through the cache for others,
+/// describing what went wrong if it can't be fetched
+///
+/// Servers are sent the login given, or else the one kept for them, if any.
+///
+/// This blocks, so is meant to be called off the main thread.
+pub fn fetch(url: &Url, credentials: Option<Credentials>) -> Result<Vec<u8>, String> {
     let mut bytes = Vec::with_capacity(512);
-    urls.iter()
-        .map(|url| {
-            let loader = PixbufLoader::new();
-            if let Ok(input) = client.get(url.clone()).send() {
-                let mut reader = BufReader::new(input);
-                bytes.clear();
-                reader.read_to_end(&mut bytes).unwrap();
-                loader.loader_write(&bytes).unwrap();
-                loader.close().unwrap();
-                if let Some(mut image) = loader.get_pixbuf() {
-                    let (width, height) = (image.get_width(), image.get_height());
-                    if width > max_width {
-                        let (new_width, new_height) = (max_width, (height * max_width) / width);
-                        image = image
-                            .scale_simple(new_width, new_height, InterpType::Bilinear)
-                            .unwrap();
-                    }
-                    Some(image)
-                } else { None }
-            } else {
-                None
+    match url.scheme() {
+        "file" => {
+            let path = url.to_file_path()
+                .map_err(|_| format!("{} is not a local path", url))?;
+            File::open(path)
+                .and_then(|mut file| file.read_to_end(&mut bytes))
+                .map_err(|err| err.to_string())?;
+        }
+        "data" => bytes = decode_data_url(url).ok_or_else(|| "Malformed data URL".to_owned())?,
+        _ => {
+            let credentials =
+                credentials.or_else(|| auth::site(url).and_then(|site| auth::lookup(&site)));
+            bytes = cache::fetch(url, credentials.as_ref())?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// An image decoded for showing
+#[derive(Clone)]
+pub enum Picture {
+    Still(Pixbuf),
+    /// An image with several frames, like an animated GIF, along with the frame it is showing
+    Animated(PixbufAnimation, PixbufAnimationIter),
+    /// A vector image, like an SVG, kept as the bytes it was decoded from so it can be drawn
+    /// again at whatever size it is shown at, along with how it looks at its natural size
+    Scalable(Vec<u8>, Pixbuf),
+}
+
+impl Picture {
+    /// The size of the image as it comes
+    pub fn size(&self) -> (i32, i32) {
+        match *self {
+            Picture::Still(ref pixbuf) |
+            Picture::Scalable(_, ref pixbuf) => (pixbuf.get_width(), pixbuf.get_height()),
+            Picture::Animated(ref animation, _) => (animation.get_width(), animation.get_height()),
+        }
+    }
+    /// Draw the image, or the frame it is showing, at a certain size in pixels
+    pub fn render(&self, width: i32, height: i32) -> Option<Pixbuf> {
+        match *self {
+            Picture::Still(ref pixbuf) => scale_pixbuf(pixbuf, width, height, InterpType::Hyper),
+            // Frames go by too fast for the slower, smoother scaling
+            Picture::Animated(_, ref frames) => {
+                scale_pixbuf(&frames.get_pixbuf(), width, height, InterpType::Bilinear)
             }
-        })
-        .collect()
+            Picture::Scalable(ref bytes, ref pixbuf) => {
+                let loader = PixbufLoader::new();
+                loader.set_size(width, height);
+                loader
+                    .loader_write(bytes)
+                    .and_then(|_| loader.close())
+                    .ok()
+                    .and_then(|_| loader.get_pixbuf())
+                    .or_else(|| scale_pixbuf(pixbuf, width, height, InterpType::Hyper))
+            }
+        }
+    }
+}
+
+/// Decode an image at its full size, describing what went wrong if it can't be decoded, like
+/// when it is in a format there is no loader for
+pub fn load_image(bytes: &[u8]) -> Result<Picture, String> {
+    let loader = PixbufLoader::new();
+    loader
+        .loader_write(bytes)
+        .and_then(|_| loader.close())
+        .map_err(|err| err.to_string())?;
+    if let Some(animation) = loader.get_animation() {
+        if !animation.is_static_image() {
+            let frames = animation.get_iter(&glib::get_current_time());
+            return Ok(Picture::Animated(animation, frames));
+        }
+    }
+    let pixbuf = loader
+        .get_pixbuf()
+        .ok_or_else(|| "Not an image".to_owned())?;
+    if loader.get_format().is_scalable() {
+        Ok(Picture::Scalable(bytes.to_vec(), pixbuf))
+    } else {
+        Ok(Picture::Still(pixbuf))
+    }
+}
+
+/// Scale an image to a certain size, unless it is already that size
+pub fn scale_pixbuf(image: &Pixbuf, width: i32, height: i32, interp: InterpType) -> Option<Pixbuf> {
+    if image.get_width() == width && image.get_height() == height {
+        Some(image.clone())
+    } else {
+        image.scale_simple(width, height, interp).ok()
+    }
+}
+
+/// Encode an image as PNG
+pub fn encode_png(image: &Pixbuf) -> Option<Vec<u8>> {
+    unsafe {
+        let mut buffer = ptr::null_mut();
+        let mut size = 0;
+        let mut error = ptr::null_mut();
+        let saved = gdk_pixbuf_ffi::gdk_pixbuf_save_to_bufferv(image.to_glib_none().0,
+                                                               &mut buffer,
+                                                               &mut size,
+                                                               "png".to_glib_none().0,
+                                                               ptr::null_mut(),
+                                                               ptr::null_mut(),
+                                                               &mut error);
+        if saved == glib_ffi::GFALSE {
+            glib_ffi::g_error_free(error);
+            return None;
+        }
+        let bytes = slice::from_raw_parts(buffer, size).to_vec();
+        glib_ffi::g_free(buffer as glib_ffi::gpointer);
+        Some(bytes)
+    }
+}
+
+/// Write an image to a new file in the `assets` folder next to a document, returning the path
+/// to it relative to the document
+pub fn save_asset(document: &Path, extension: &str, bytes: &[u8]) -> io::Result<String> {
+    let assets = document.parent().unwrap_or(Path::new("")).join("assets");
+    fs::create_dir_all(&assets)?;
+    let name = (1..)
+        .map(|n: usize| format!("image-{}.{}", n, extension))
+        .find(|name| !assets.join(name).exists())
+        .unwrap();
+    let mut file = File::create(assets.join(&name))?;
+    file.write_all(bytes)?;
+    Ok(format!("assets/{}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(url: &str) -> Option<Vec<u8>> {
+        decode_data_url(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn data_urls_are_decoded() {
+        assert_eq!(decode("data:image/png;base64,iVBORw0K"),
+                   Some(b"\x89PNG\r\n".to_vec()));
+        // Base64 can be wrapped, and percent-encoded too
+        assert_eq!(decode("data:;base64,aGVs%0AbG8%3D"), Some(b"hello".to_vec()));
+        assert_eq!(decode("data:text/plain,a%20b%2Cc"), Some(b"a b,c".to_vec()));
+        assert_eq!(decode("data:text/plain;charset=utf-8,%E2%9C%93"),
+                   Some("✓".as_bytes().to_vec()));
+        assert_eq!(decode("data:,"), Some(Vec::new()));
+    }
+
+    #[test]
+    fn malformed_data_urls_are_turned_down() {
+        assert_eq!(decode("data:image/png;base64"), None);
+        assert_eq!(decode("data:image/png;base64,not*base64"), None);
+        assert_eq!(decode("data:;base64,aGVsbG8=="), None);
+    }
+
+    #[test]
+    fn local_and_embedded_images_are_fetched_without_the_network() {
+        let path = env::temp_dir().join("litepad-util-fetch.png");
+        File::create(&path).unwrap().write_all(b"\x89PNG").unwrap();
+        let url = Url::from_file_path(&path).unwrap();
+        assert_eq!(fetch(&url, None), Ok(b"\x89PNG".to_vec()));
+        fs::remove_file(&path).unwrap();
+        assert!(fetch(&url, None).is_err());
+        assert_eq!(fetch(&Url::parse("data:,a%20b").unwrap(), None), Ok(b"a b".to_vec()));
+        assert_eq!(fetch(&Url::parse("data:;base64,!").unwrap(), None),
+                   Err("Malformed data URL".to_owned()));
+    }
+
+    /// A black 1x1 GIF
+    const STILL_GIF: &'static [u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff\
+                                     !\xf9\x04\x01\x00\x00\x00\x00\
+                                     ,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+    /// A 1x1 GIF that flips between black and white, looping forever
+    const ANIMATED_GIF: &'static [u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\
+                                        \x00\x00\x00\xff\xff\xff\
+                                        !\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00\
+                                        !\xf9\x04\x00\x0a\x00\x00\x00\
+                                        ,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00\
+                                        !\xf9\x04\x00\x0a\x00\x00\x00\
+                                        ,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02L\x01\x00;";
+
+    #[test]
+    fn images_are_decoded_by_kind() {
+        match load_image(STILL_GIF) {
+            Ok(picture @ Picture::Still(_)) => assert_eq!(picture.size(), (1, 1)),
+            _ => panic!("a still GIF wasn't decoded as a still image"),
+        }
+        match load_image(ANIMATED_GIF) {
+            Ok(picture @ Picture::Animated(..)) => assert_eq!(picture.size(), (1, 1)),
+            _ => panic!("an animated GIF wasn't decoded as an animation"),
+        }
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"/>"#;
+        match load_image(svg) {
+            Ok(picture @ Picture::Scalable(..)) => assert_eq!(picture.size(), (4, 2)),
+            _ => panic!("an SVG wasn't decoded as a scalable image"),
+        }
+    }
+
+    #[test]
+    fn broken_images_are_turned_down() {
+        assert!(load_image(b"not an image").is_err());
+        assert!(load_image(&STILL_GIF[..20]).is_err());
+    }
 }
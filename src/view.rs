@@ -1,30 +1,272 @@
 use app::App;
-use source::Source;
-use std::collections::HashMap;
-use std::io::Error;
+use auth::{self, Challenge, Credentials};
+use encoding::{Encoding, Format};
+use source::{Error, Remote, Source};
+use std::cell::Cell;
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
 
-use pulldown_cmark::{Parser, Event, Tag};
+use document::{Alignment, Block, BlockBuilder, Container, Definition, Document, Inline,
+               InlineBuilder, Original, Reference, Style};
+use document;
 
-use hyper::Url;
+use cairo;
 
-use util;
+use gdk;
+use gdk::enums::key;
+use gdk::prelude::ContextExt;
+
+use util::{self, Picture};
 
 use gdk_pixbuf::Pixbuf;
 
+use glib;
+use glib::translate::ToGlib;
+
+use gtk;
 use gtk::*;
-use std::boxed::Box;
+
+use base64;
 
 use webbrowser;
 
-fn is_block(tag: &Tag) -> bool {
-    match *tag {
-        Tag::Header(_) |
-        Tag::CodeBlock(_) |
-        Tag::Item => true,
-        _ => false,
+/// Extend a list of tag names with another
+fn with_tag<'a>(tags: &[&'a str], tag: &'a str) -> Vec<&'a str> {
+    let mut tags = tags.to_vec();
+    tags.push(tag);
+    tags
+}
+
+/// How far each level of list or quote nesting indents its lines
+const INDENT: i32 = 24;
+/// The space left below each item of a loose list
+const LOOSE_LIST_SPACING: i32 = 8;
+/// The size of the square shown in place of an image while it loads
+const PLACEHOLDER_SIZE: i32 = 32;
+/// The widest images are shown before the view has been given a width
+const DEFAULT_IMAGE_WIDTH: i32 = 500;
+/// How much zooming in or out changes the size of an image by
+const ZOOM_STEP: f64 = 1.25;
+/// The shortest time in milliseconds a frame of an animated image is shown for
+const MIN_FRAME_DELAY: i32 = 20;
+
+/// Read the attributes of a HTML tag, given the text between its name and its end, decoding
+/// the common character references in their values
+fn html_attributes(text: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = text.trim_left();
+    while !rest.is_empty() {
+        let name_end = rest.find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or_else(|| rest.len());
+        let name = rest[..name_end].to_lowercase();
+        rest = rest[name_end..].trim_left();
+        let mut value = String::new();
+        if rest.starts_with('=') {
+            rest = rest[1..].trim_left();
+            let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'');
+            let (raw, after) = match quote {
+                Some(quote) => {
+                    let end = rest[1..].find(quote).map_or(rest.len(), |end| end + 1);
+                    (&rest[1..end], &rest[min(end + 1, rest.len())..])
+                }
+                None => {
+                    let end = rest.find(char::is_whitespace).unwrap_or_else(|| rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            value = raw.replace("&quot;", "\"")
+                .replace("&#39;", "'")
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&amp;", "&");
+            rest = after.trim_left();
+        }
+        // A value without a name, after a stray `=`, is dropped
+        if !name.is_empty() {
+            attributes.entry(name).or_insert(value);
+        }
+    }
+    attributes
+}
+
+/// Find the tag of the innermost quote among some containers
+fn innermost_quote(containers: &[(TextTag, usize, Container)]) -> Option<TextTag> {
+    containers
+        .iter()
+        .rev()
+        .find(|&&(_, _, ref container)| match *container {
+                  Container::BlockQuote { .. } => true,
+                  _ => false,
+              })
+        .map(|&(ref tag, _, _)| tag.clone())
+}
+
+/// Show a footnote label on its marker as superscript
+fn set_footnote_label(marker: &Label, label: &str) {
+    let label = label.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    marker.set_markup(&format!("<sup>{}</sup>", label));
+}
+
+/// Get the marker shown before the `index`th item of a list
+fn list_marker(start: Option<usize>, index: usize) -> String {
+    match start {
+        Some(start) => format!("{}. ", start + index),
+        None => "• ".to_string(),
+    }
+}
+
+/// Get the start of the line `iter` is on and the start of the line after it
+fn line_bounds(iter: &TextIter) -> (TextIter, TextIter) {
+    let mut start = iter.clone();
+    start.set_line_offset(0);
+    let mut end = start.clone();
+    if !end.forward_line() {
+        end.forward_to_end();
+    }
+    (start, end)
+}
+
+/// Parse a Markdown list marker typed at the start of a line, giving the start number of
+/// ordered lists and the length of the marker
+fn list_prefix(line: &str) -> Option<(Option<usize>, usize)> {
+    for bullet in &["- ", "* ", "+ "] {
+        if line.starts_with(bullet) && line.len() > 2 {
+            return Some((None, 2));
+        }
+    }
+    let digits = line.chars().take_while(|c| c.is_digit(10)).count();
+    let rest = &line[digits..];
+    if digits > 0 && digits <= 9 && (rest.starts_with(". ") || rest.starts_with(") ")) &&
+       rest.len() > 2 {
+        return line[..digits].parse().ok().map(|start| (Some(start), digits + 2));
+    }
+    None
+}
+
+/// Show the dialog for entering a link, filled in from `link` if given, and call `done` with the
+/// URL, the title and, for a reference, the label entered
+pub fn link_dialog<F>(link: Option<&LinkInfo>, done: F)
+    where F: Fn(String, String, Option<String>) + 'static
+{
+    // Build from glade
+    let builder = Builder::new_from_string(include_str!("../link-dialog.glade"));
+    let url: Entry = builder.get_object("url").unwrap();
+    let title: Entry = builder.get_object("title").unwrap();
+    let reference: CheckButton = builder.get_object("reference").unwrap();
+    let label: Entry = builder.get_object("label").unwrap();
+    let dialog: Dialog = builder.get_object("dialog").unwrap();
+    let ok: Button = builder.get_object("ok").unwrap();
+    if let Some(link) = link {
+        url.set_text(&link.url);
+        title.set_text(&link.title);
+        if let Some(ref link_reference) = link.reference {
+            reference.set_active(true);
+            label.set_sensitive(true);
+            label.set_text(link_reference.label());
+        }
     }
+    let label2 = label.clone();
+    reference.connect_toggled(move |reference| label2.set_sensitive(reference.get_active()));
+    let dialog2 = dialog.clone();
+    ok.connect_clicked(move |_| {
+        let label = if reference.get_active() {
+            Some(label.get_text().unwrap_or_default())
+        } else {
+            None
+        };
+        done(url.get_text().unwrap_or_default().trim().to_owned(),
+             title.get_text().unwrap_or_default(),
+             label);
+        dialog2.destroy();
+    });
+    dialog.show_all();
+    dialog.run();
 }
+
+/// Show the dialog for entering an image, filled in from `image` if given, and call `done` with
+/// the URL, the alt text and the title entered
+///
+/// The URL of an image already shown can't be changed.
+pub fn image_dialog<F>(image: Option<&ImageInfo>, done: F)
+    where F: Fn(String, String, String) + 'static
+{
+    // Build from glade
+    let builder = Builder::new_from_string(include_str!("../image-dialog.glade"));
+    let url: Entry = builder.get_object("url").unwrap();
+    let alt: Entry = builder.get_object("alt").unwrap();
+    let title: Entry = builder.get_object("title").unwrap();
+    let dialog: Dialog = builder.get_object("dialog").unwrap();
+    let ok: Button = builder.get_object("ok").unwrap();
+    if let Some(image) = image {
+        url.set_text(&image.url);
+        url.set_sensitive(false);
+        alt.set_text(&image.alt);
+        title.set_text(&image.title);
+    }
+    let dialog2 = dialog.clone();
+    ok.connect_clicked(move |_| {
+        done(url.get_text().unwrap_or_default().trim().to_owned(),
+             alt.get_text().unwrap_or_default(),
+             title.get_text().unwrap_or_default());
+        dialog2.destroy();
+    });
+    dialog.show_all();
+    dialog.run();
+}
+
+/// Show the dialog for logging in to a site, saying if the last login was turned down, and
+/// return the login entered and whether to remember it, or nothing if it was cancelled
+pub fn login_dialog(site: &str,
+                    challenge: &Challenge,
+                    rejected: bool)
+                    -> Option<(Credentials, bool)> {
+    // Build from glade
+    let builder = Builder::new_from_string(include_str!("../login-dialog.glade"));
+    let message: Label = builder.get_object("message").unwrap();
+    let user: Entry = builder.get_object("user").unwrap();
+    let password: Entry = builder.get_object("password").unwrap();
+    let remember: CheckButton = builder.get_object("remember").unwrap();
+    let dialog: Dialog = builder.get_object("dialog").unwrap();
+    dialog.add_button("Cancel", 1);
+    dialog.add_button("Log in", 0);
+    dialog.set_default_response(0);
+    let mut text = match challenge.realm {
+        Some(ref realm) => format!("{} needs a login for \"{}\".", site, realm),
+        None => format!("{} needs a login.", site),
+    };
+    if rejected {
+        text.push_str(" The last one was turned down.");
+    }
+    message.set_text(&text);
+    dialog.show_all();
+    // Tokens stand in for both the user name and the password
+    if challenge.bearer {
+        user.hide();
+        password.set_placeholder_text(Some("Token"));
+    }
+    let response = dialog.run();
+    let secret = password.get_text().unwrap_or_default();
+    let credentials = if challenge.bearer {
+        Credentials::Bearer(secret.trim().to_owned())
+    } else {
+        Credentials::Basic {
+            user: user.get_text().unwrap_or_default(),
+            password: secret,
+        }
+    };
+    let remember = remember.get_active();
+    dialog.destroy();
+    if response == 0 {
+        Some((credentials, remember))
+    } else {
+        None
+    }
+}
+
 #[derive(Clone)]
 pub struct MetaIter<T> {
     pub start: TextIter,
@@ -38,8 +280,281 @@ pub struct View {
     pub text: TextBuffer,
     pub view: TextView,
     pub window: ScrolledWindow,
+    /// What the tab shows, the text along with a bar above it for errors
+    pub page: Box,
+    pub info_bar: InfoBar,
+    /// The message shown in the info bar
+    pub message: Label,
     pub source: Arc<Mutex<Source>>,
-    pub image_urls: Arc<Mutex<HashMap<Pixbuf, Url>>>,
+    pub images: Arc<Mutex<HashMap<TextChildAnchor, Image>>>,
+    pub pasted_images: Arc<Mutex<PastedImages>>,
+    /// Whether saving keeps the previous version of the file as `file~`
+    pub backup: Arc<Mutex<bool>>,
+    /// The version of the document on its server, for documents loaded from one
+    pub remote: Arc<Mutex<Remote>>,
+    /// How the document is written, in its source
+    pub format: Arc<Mutex<Format>>,
+    pub links: Arc<Mutex<HashMap<TextMark, LinkInfo>>>,
+    /// The link reference definitions, kept apart from the text
+    pub definitions: Arc<Mutex<Vec<Definition>>>,
+    pub code_blocks: Arc<Mutex<HashMap<TextTag, String>>>,
+    pub lists: Arc<Mutex<HashMap<TextTag, ListInfo>>>,
+    pub quotes: Arc<Mutex<HashMap<TextTag, QuoteInfo>>>,
+    pub tables: Arc<Mutex<HashMap<TextChildAnchor, Table>>>,
+    pub rules: Arc<Mutex<HashSet<TextChildAnchor>>>,
+    pub tasks: Arc<Mutex<HashMap<TextChildAnchor, CheckButton>>>,
+    pub footnotes: Arc<Mutex<HashMap<TextTag, FootnoteInfo>>>,
+    /// The markers of footnote references, showing their labels
+    pub footnote_refs: Arc<Mutex<HashMap<TextChildAnchor, Label>>>,
+    pub original: Arc<Mutex<Option<Original>>>,
+}
+
+/// A link shown in a view, tagged "link" and starting at an anonymous mark
+#[derive(Clone, Debug)]
+pub struct LinkInfo {
+    pub url: String,
+    pub title: String,
+    /// How the link names its definition, if it is written as a reference
+    pub reference: Option<Reference>,
+}
+
+/// An image as written in a document
+#[derive(Clone, Debug, Default)]
+pub struct ImageInfo {
+    /// The URL or path as written, before being resolved against the source
+    pub url: String,
+    pub alt: String,
+    pub title: String,
+    /// How the image names its definition, if it is written as a reference
+    pub reference: Option<Reference>,
+    /// Why the image couldn't be loaded, if it couldn't
+    pub error: Option<String>,
+    /// The `<img>` tag the image is written as, if it is written in HTML, which is saved as is
+    pub html: Option<String>,
+    /// The width asked for by an `<img>` tag, in pixels
+    pub width: Option<i32>,
+    /// The height asked for by an `<img>` tag, in pixels
+    pub height: Option<i32>,
+}
+
+impl ImageInfo {
+    /// Read an image written as a lone `<img>` tag, with its `src`, `alt`, `title`, `width`
+    /// and `height` attributes
+    pub fn from_html(html: &str) -> Option<ImageInfo> {
+        let tag = html.trim();
+        // The name has to end there, so `<imgs>` isn't taken for an image
+        let is_img = tag.get(..4).map_or(false, |name| name.eq_ignore_ascii_case("<img")) &&
+                     tag[4..].starts_with(|c: char| c.is_whitespace() || c == '/' || c == '>');
+        if !is_img || !tag.ends_with('>') || tag[1..].contains('<') {
+            return None;
+        }
+        let attributes = html_attributes(tag[4..tag.len() - 1].trim_right_matches('/'));
+        let size = |name: &str| {
+            attributes
+                .get(name)
+                .and_then(|value| value.trim_right_matches("px").parse().ok())
+                .filter(|&size: &i32| size > 0)
+        };
+        attributes
+            .get("src")
+            .map(|src| {
+                     ImageInfo {
+                         url: src.clone(),
+                         alt: attributes.get("alt").cloned().unwrap_or_default(),
+                         title: attributes.get("title").cloned().unwrap_or_default(),
+                         html: Some(tag.to_owned()),
+                         width: size("width"),
+                         height: size("height"),
+                         ..ImageInfo::default()
+                     }
+                 })
+    }
+    /// The text to show when hovering over the image
+    pub fn tooltip(&self) -> Option<&str> {
+        if let Some(ref error) = self.error {
+            Some(error)
+        } else if !self.title.is_empty() {
+            Some(&self.title)
+        } else if !self.alt.is_empty() {
+            Some(&self.alt)
+        } else {
+            None
+        }
+    }
+}
+
+/// Work out the size to show an image of a `natural` size at, fitting it into `max_width`
+/// before zooming it
+///
+/// Images are as big as the `<img>` tag they're written as `asks`, keeping their aspect ratio
+/// when only one of the width and height is given, and as big as they are otherwise.
+fn fit_size(natural: (i32, i32),
+            asks: (Option<i32>, Option<i32>),
+            max_width: i32,
+            zoom: f64)
+            -> (i32, i32) {
+    let (natural_width, natural_height) = natural;
+    let (width, height) = match asks {
+        (Some(width), Some(height)) => (width, height),
+        (Some(width), None) => (width, natural_height * width / natural_width),
+        (None, Some(height)) => (natural_width * height / natural_height, height),
+        (None, None) => (natural_width, natural_height),
+    };
+    let (width, height) = if width > max_width {
+        (max_width, height * max_width / width)
+    } else {
+        (width, height)
+    };
+    (max((width as f64 * zoom) as i32, 1), max((height as f64 * zoom) as i32, 1))
+}
+
+/// An image shown in a view, drawn by an area at a child anchor
+#[derive(Clone)]
+pub struct Image {
+    pub info: ImageInfo,
+    /// The widget at the anchor, holding the area and taking clicks on it
+    pub widget: EventBox,
+    pub area: DrawingArea,
+    /// Shows the alt text of an image that couldn't be loaded in its place
+    pub fallback: Label,
+    /// The image at its full size, once loaded
+    pub picture: Option<Picture>,
+    /// How far the image is zoomed in, which is only for viewing so isn't saved
+    pub zoom: f64,
+    /// The image as last drawn, scaled to the device pixels of the area
+    scaled: Option<Pixbuf>,
+}
+
+impl Image {
+    /// Work out the size to show the image at, fitting it into `max_width` before zooming it
+    pub fn size(&self, max_width: i32) -> (i32, i32) {
+        let natural = match self.picture {
+            Some(ref picture) => picture.size(),
+            None => (PLACEHOLDER_SIZE, PLACEHOLDER_SIZE),
+        };
+        fit_size(natural, (self.info.width, self.info.height), max_width, self.zoom)
+    }
+    /// Show the alt text in place of the image if it couldn't be loaded
+    fn update_fallback(&self) {
+        let failed = self.picture.is_none() && self.info.error.is_some();
+        self.fallback.set_text(&self.info.alt);
+        self.fallback.set_visible(failed);
+        self.area.set_visible(!failed);
+    }
+    /// Draw the image to fill its area, scaled to the device pixels of the screen it is on so
+    /// that it stays sharp on high resolution screens
+    fn draw(&mut self, cr: &cairo::Context) {
+        let scale = self.area.get_scale_factor();
+        let width = self.area.get_allocated_width() * scale;
+        let height = self.area.get_allocated_height() * scale;
+        let picture = match self.picture {
+            Some(ref picture) => picture,
+            None => {
+                cr.set_source_rgb(0.8, 0.8, 0.8);
+                cr.paint();
+                return;
+            }
+        };
+        // Scaling is slow, so is only redone when the size or frame changes
+        let stale = self.scaled
+            .as_ref()
+            .map_or(true, |scaled| scaled.get_width() != width || scaled.get_height() != height);
+        if stale {
+            self.scaled = picture.render(width, height);
+        }
+        if let Some(ref scaled) = self.scaled {
+            cr.scale(1. / scale as f64, 1. / scale as f64);
+            cr.set_source_pixbuf(scaled, 0., 0.);
+            cr.paint();
+        }
+    }
+}
+
+/// Where images pasted into a view are kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PastedImages {
+    /// Embedded in the document as `data:` URLs
+    Embed,
+    /// Written to an `assets` folder next to the document, which only works for files
+    Assets,
+}
+
+/// A list shown in a view, marked by an anonymous tag over its lines
+#[derive(Clone, Debug)]
+pub struct ListInfo {
+    pub id: usize,
+    /// How many lists and quotes deep this list is nested, starting at one
+    pub depth: usize,
+    pub start: Option<usize>,
+    pub tight: bool,
+}
+
+impl ListInfo {
+    pub fn container(&self) -> Container {
+        Container::List {
+            id: self.id,
+            start: self.start,
+            tight: self.tight,
+        }
+    }
+}
+
+/// A block quote shown in a view, marked by an anonymous tag over its lines
+#[derive(Clone, Debug)]
+pub struct QuoteInfo {
+    pub id: usize,
+    /// How many lists and quotes deep this quote is nested, starting at one
+    pub depth: usize,
+}
+
+/// A footnote definition shown in a view, marked by an anonymous tag over its lines
+#[derive(Clone)]
+pub struct FootnoteInfo {
+    pub id: usize,
+    /// How many lists and quotes deep this definition is nested, starting at one
+    pub depth: usize,
+    /// The marker starting the definition, showing its label
+    pub marker: Label,
+}
+
+impl FootnoteInfo {
+    pub fn container(&self) -> Container {
+        Container::FootnoteDefinition {
+            id: self.id,
+            label: self.marker.get_text().unwrap_or_default(),
+        }
+    }
+}
+
+/// A table shown in a view as a grid of entries, one per cell
+#[derive(Clone)]
+pub struct Table {
+    pub alignments: Vec<Alignment>,
+    /// The entry for each cell of each row, along with the content it was loaded with
+    pub cells: Vec<Vec<(Entry, Vec<Inline>)>>,
+}
+
+impl Table {
+    /// Read the rows back from the grid, keeping the formatting of cells left unedited
+    pub fn rows(&self) -> Vec<Vec<Vec<Inline>>> {
+        self.cells
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|&(ref entry, ref content)| {
+                        let text = entry.get_text().unwrap_or_default();
+                        if text == document::plain_text(content) {
+                            content.clone()
+                        } else if text.is_empty() {
+                            Vec::new()
+                        } else {
+                            vec![Inline::Text(text)]
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
 }
 
 impl View {
@@ -47,64 +562,582 @@ impl View {
         let buffer = TextBuffer::new(Some(tags));
         let view = TextView::new_with_buffer(&buffer);
         let window = ScrolledWindow::new(None, None);
+        let info_bar = InfoBar::new();
+        info_bar.set_message_type(MessageType::Error);
+        info_bar.set_show_close_button(true);
+        info_bar.connect_response(|info_bar, _| info_bar.hide());
+        // Only shown once there is something to show
+        info_bar.set_no_show_all(true);
+        let message = Label::new(None);
+        message.set_line_wrap(true);
+        message.show();
+        if let Some(content) = info_bar
+               .get_content_area()
+               .and_then(|content| content.downcast::<gtk::Container>().ok()) {
+            content.add(&message);
+        }
+        let page = Box::new(Orientation::Vertical, 0);
+        page.pack_start(&info_bar, false, false, 0);
+        page.pack_start(&window, true, true, 0);
+        let pasted_images = match source {
+            Source::File(_) => PastedImages::Assets,
+            _ => PastedImages::Embed,
+        };
         View {
             label: Label::new(format!("{}", source).as_str()),
             text: buffer,
             view,
             window,
+            page,
+            info_bar,
+            message,
             source: Arc::new(Mutex::new(source)),
-            image_urls: Arc::new(Mutex::new(HashMap::new())),
+            images: Arc::new(Mutex::new(HashMap::new())),
+            pasted_images: Arc::new(Mutex::new(pasted_images)),
+            backup: Arc::new(Mutex::new(false)),
+            remote: Arc::new(Mutex::new(Remote::default())),
+            format: Arc::new(Mutex::new(Format::default())),
+            links: Arc::new(Mutex::new(HashMap::new())),
+            definitions: Arc::new(Mutex::new(Vec::new())),
+            code_blocks: Arc::new(Mutex::new(HashMap::new())),
+            lists: Arc::new(Mutex::new(HashMap::new())),
+            quotes: Arc::new(Mutex::new(HashMap::new())),
+            tables: Arc::new(Mutex::new(HashMap::new())),
+            rules: Arc::new(Mutex::new(HashSet::new())),
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+            footnotes: Arc::new(Mutex::new(HashMap::new())),
+            footnote_refs: Arc::new(Mutex::new(HashMap::new())),
+            original: Arc::new(Mutex::new(None)),
         }
     }
-    pub fn link(&self, url: Url) {
+    /// Link the selection, either inline or, when given a label, as a reference to a definition
+    /// with that label
+    pub fn link(&self, url: &str, title: &str, label: Option<String>) {
         if let Some((start, end)) = self.text.get_selection_bounds() {
             self.text.apply_tag_by_name("link", &start, &end);
-            let url = url.to_string();
-            let mark = TextMark::new(Some(url.as_str()), false);
+            let mark = TextMark::new(None, false);
             self.text.add_mark(&mark, &start);
+            self.set_link(&mark, url, title, label);
+        }
+    }
+    /// Change where the link starting at `mark` points, adding or updating the definition with
+    /// the label given
+    ///
+    /// An empty label is replaced by the lowest number not already used as one.
+    pub fn set_link(&self, mark: &TextMark, url: &str, title: &str, label: Option<String>) {
+        let old = self.links
+            .lock()
+            .unwrap()
+            .get(mark)
+            .and_then(|link| link.reference.clone());
+        let reference = label.map(|label| {
+            let label = if label.trim().is_empty() {
+                self.next_definition_label()
+            } else {
+                label.trim().to_owned()
+            };
+            self.define(&label, url, title);
+            match old {
+                // Keep how the reference was written while it names the same definition
+                Some(old) if old.label() == label => old,
+                _ => {
+                    let (start, end) = self.link_bounds(mark);
+                    let text = self.text.get_slice(&start, &end, false).unwrap_or_default();
+                    if document::normalize_label(&text) == document::normalize_label(&label) {
+                        Reference::Shortcut(label)
+                    } else {
+                        Reference::Full(label)
+                    }
+                }
+            }
+        });
+        self.links
+            .lock()
+            .unwrap()
+            .insert(mark.clone(),
+                    LinkInfo {
+                        url: url.to_owned(),
+                        title: title.to_owned(),
+                        reference,
+                    });
+        self.text.set_modified(true);
+    }
+    /// Turn the link starting at `mark` back into plain text
+    pub fn remove_link(&self, mark: &TextMark) {
+        let (start, end) = self.link_bounds(mark);
+        self.text.remove_tag_by_name("link", &start, &end);
+        self.text.delete_mark(mark);
+        self.links.lock().unwrap().remove(mark);
+        self.text.set_modified(true);
+    }
+    /// Find the mark of the link starting at `iter`
+    fn link_at(&self, iter: &TextIter) -> Option<TextMark> {
+        let links = self.links.lock().unwrap();
+        iter.get_marks()
+            .into_iter()
+            .find(|mark| links.contains_key(mark))
+    }
+    /// Find the mark starting the link `iter` is in
+    pub fn link_containing(&self, iter: &TextIter) -> Option<TextMark> {
+        let link = self.text.get_tag_table().unwrap().lookup("link").unwrap();
+        let mut iter = iter.clone();
+        while iter.has_tag(&link) {
+            if let Some(mark) = self.link_at(&iter) {
+                return Some(mark);
+            }
+            if !iter.backward_char() {
+                break;
+            }
         }
+        None
+    }
+    /// Find the bounds of the text of the link starting at `mark`
+    fn link_bounds(&self, mark: &TextMark) -> (TextIter, TextIter) {
+        let link = self.text.get_tag_table().unwrap().lookup("link").unwrap();
+        let start = self.text.get_iter_at_mark(mark);
+        let mut end = start.clone();
+        while end.forward_char() && end.has_tag(&link) && self.link_at(&end).is_none() {}
+        (start, end)
+    }
+    /// Show a popover by the link starting at `mark`, to edit, open or remove it
+    fn link_popover(&self, mark: &TextMark) {
+        let link = match self.links.lock().unwrap().get(mark) {
+            Some(link) => link.clone(),
+            None => return,
+        };
+        // Build from glade
+        let builder = Builder::new_from_string(include_str!("../link-popover.glade"));
+        let popover: Popover = builder.get_object("popover").unwrap();
+        let url: Label = builder.get_object("url").unwrap();
+        let title: Label = builder.get_object("title").unwrap();
+        let edit: Button = builder.get_object("edit").unwrap();
+        let open: Button = builder.get_object("open").unwrap();
+        let remove: Button = builder.get_object("remove").unwrap();
+        url.set_text(&link.url);
+        title.set_text(&link.title);
+        let has_title = !link.title.is_empty();
+        let location = self.view.get_iter_location(&self.text.get_iter_at_mark(mark));
+        let (x, y) = self.view
+            .buffer_to_window_coords(TextWindowType::Widget, location.x, location.y);
+        popover.set_relative_to(Some(&self.view));
+        popover.set_pointing_to(&gdk::Rectangle {
+                                     x,
+                                     y,
+                                     width: location.width,
+                                     height: location.height,
+                                 });
+        popover.connect_closed(|popover| popover.destroy());
+        let me = self.clone();
+        let popover2 = popover.clone();
+        let mark2 = mark.clone();
+        let link2 = link.clone();
+        edit.connect_clicked(move |_| {
+            popover2.hide();
+            let me = me.clone();
+            let mark = mark2.clone();
+            link_dialog(Some(&link2),
+                        move |url, title, label| me.set_link(&mark, &url, &title, label));
+        });
+        let me = self.clone();
+        let popover2 = popover.clone();
+        open.connect_clicked(move |_| {
+                                 me.open_link(&link.url);
+                                 popover2.hide();
+                             });
+        let me = self.clone();
+        let popover2 = popover.clone();
+        let mark = mark.clone();
+        remove.connect_clicked(move |_| {
+                                   me.remove_link(&mark);
+                                   popover2.hide();
+                               });
+        popover.show_all();
+        title.set_visible(has_title);
+    }
+    /// Point the definition labelled `label` at `url`, adding it if there isn't one
+    fn define(&self, label: &str, url: &str, title: &str) {
+        let mut definitions = self.definitions.lock().unwrap();
+        let normalized = document::normalize_label(label);
+        if let Some(definition) = definitions
+               .iter_mut()
+               .find(|definition| document::normalize_label(&definition.label) == normalized) {
+            definition.url = url.to_owned();
+            definition.title = title.to_owned();
+            return;
+        }
+        definitions.push(Definition {
+                             label: label.to_owned(),
+                             url: url.to_owned(),
+                             title: title.to_owned(),
+                         });
+    }
+    /// Find the lowest number not already used as the label of a definition
+    fn next_definition_label(&self) -> String {
+        let definitions = self.definitions.lock().unwrap();
+        (1..)
+            .map(|n: usize| n.to_string())
+            .find(|label| !definitions.iter().any(|definition| definition.label == *label))
+            .unwrap()
     }
-    pub fn image(&self, url: Url) {
+    /// Replace the selection with an image, using the selected text as its alt text if none is
+    /// given
+    ///
+    /// The URL may also be a path, relative to the document or absolute.
+    pub fn image(&self, url: &str, alt: &str, title: &str) {
         if let Some((mut start, mut end)) = self.text.get_selection_bounds() {
-            let mut image_urls = self.image_urls.lock().unwrap();
-            let ref pixbuf = util::load_pixbufs(&[url.clone()], 500)[0];
-            // If the image has loaded
-            if let Some(ref pixbuf) = *pixbuf {
-                image_urls.insert(pixbuf.clone(), url);
-                let alt = self.text.get_slice(&start, &end, false);
-                self.text.insert_pixbuf(&mut start, pixbuf);
-                self.text.delete(&mut start, &mut end);
+            let alt = if alt.is_empty() {
+                self.text.get_slice(&start, &end, false).unwrap_or_default()
+            } else {
+                alt.to_owned()
+            };
+            self.text.delete(&mut start, &mut end);
+            self.insert_image(&mut start,
+                              ImageInfo {
+                                  url: url.to_owned(),
+                                  alt,
+                                  title: title.to_owned(),
+                                  ..ImageInfo::default()
+                              });
+        }
+    }
+    /// Insert an area showing an image at `iter`, returning its anchor
+    fn add_image(&self,
+                 iter: &mut TextIter,
+                 info: ImageInfo,
+                 picture: Option<Picture>)
+                 -> Option<TextChildAnchor> {
+        let anchor = match self.text.create_child_anchor(iter) {
+            Some(anchor) => anchor,
+            None => return None,
+        };
+        let widget = EventBox::new();
+        let area = DrawingArea::new();
+        let fallback = Label::new(None);
+        let container = Box::new(Orientation::Horizontal, 0);
+        container.add(&area);
+        container.add(&fallback);
+        widget.add(&container);
+        widget.set_tooltip_text(info.tooltip());
+        let images = self.images.clone();
+        let drawn = anchor.clone();
+        area.connect_draw(move |_, cr| {
+                              if let Some(image) = images.lock().unwrap().get_mut(&drawn) {
+                                  image.draw(cr);
+                              }
+                              Inhibit(false)
+                          });
+        // Clicking an image edits it, while right-clicking it shows a menu for zooming it
+        let me = self.clone();
+        let clicked = anchor.clone();
+        widget.connect_button_press_event(move |_, ev| {
+            match ev.get_button() {
+                1 => me.edit_image(&clicked),
+                3 => me.image_menu(&clicked, ev.get_time()),
+                _ => return Inhibit(false),
+            }
+            Inhibit(true)
+        });
+        self.view.add_child_at_anchor(&widget, &anchor);
+        widget.show_all();
+        let image = Image {
+            info,
+            widget,
+            area,
+            fallback,
+            picture,
+            zoom: 1.,
+            scaled: None,
+        };
+        image.update_fallback();
+        self.images.lock().unwrap().insert(anchor.clone(), image);
+        self.resize_image(&anchor);
+        Some(anchor)
+    }
+    /// Insert an image at `iter`, showing a placeholder while it loads in the background
+    fn insert_image(&self, iter: &mut TextIter, image: ImageInfo) {
+        let resolved = self.source.lock().unwrap().resolve(&image.url);
+        // The login for the document is only sent along for images on the same server
+        let same_server = match *self.source.lock().unwrap() {
+            Source::Url(ref document) => {
+                resolved
+                    .as_ref()
+                    .map_or(false, |url| auth::site(url) == auth::site(document))
+            }
+            _ => false,
+        };
+        let credentials = if same_server {
+            self.remote.lock().unwrap().credentials.clone()
+        } else {
+            None
+        };
+        let anchor = match self.add_image(iter, image, None) {
+            Some(anchor) => anchor,
+            None => return,
+        };
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let fetched = match resolved {
+                Some(url) => util::fetch(&url, credentials),
+                None => Err("Not a URL or path".to_owned()),
+            };
+            sender.send(fetched).ok();
+        });
+        let me = self.clone();
+        timeout_add(50, move || match receiver.try_recv() {
+            Ok(fetched) => {
+                me.image_loaded(&anchor, fetched);
+                Continue(false)
+            }
+            Err(TryRecvError::Empty) => Continue(true),
+            Err(TryRecvError::Disconnected) => Continue(false),
+        });
+    }
+    /// Show an image in place of its placeholder once fetched, or if it couldn't be, its alt
+    /// text or failing that a broken image
+    fn image_loaded(&self, anchor: &TextChildAnchor, fetched: Result<Vec<u8>, String>) {
+        // The image might have been deleted while loading
+        if anchor.get_deleted() {
+            self.images.lock().unwrap().remove(anchor);
+            return;
+        }
+        let (picture, error) = match fetched.and_then(|bytes| util::load_image(&bytes)) {
+            Ok(picture) => (Some(picture), None),
+            Err(error) => (None, Some(error)),
+        };
+        if let Some(image) = self.images.lock().unwrap().get_mut(anchor) {
+            image.picture = if picture.is_none() && image.info.alt.is_empty() {
+                self.broken_image().map(Picture::Still)
+            } else {
+                picture
+            };
+            image.info.error = error;
+            image.widget.set_tooltip_text(image.info.tooltip());
+            image.update_fallback();
+        }
+        self.resize_image(anchor);
+        self.animate(anchor);
+    }
+    /// Show the next frame of an animated image once the one showing has been up long enough,
+    /// until the image is deleted or its tab is closed
+    fn animate(&self, anchor: &TextChildAnchor) {
+        let delay = match self.images.lock().unwrap().get(anchor) {
+            Some(&Image { picture: Some(Picture::Animated(_, ref frames)), .. }) => {
+                frames.get_delay_time()
             }
+            _ => return,
+        };
+        // Animations that don't loop stay on their last frame
+        if delay < 0 {
+            return;
+        }
+        let me = self.clone();
+        let anchor = anchor.clone();
+        timeout_add(max(delay, MIN_FRAME_DELAY) as u32, move || {
+            if anchor.get_deleted() || me.page.get_parent().is_none() {
+                me.images.lock().unwrap().remove(&anchor);
+                return Continue(false);
+            }
+            if let Some(image) = me.images.lock().unwrap().get_mut(&anchor) {
+                if let Some(Picture::Animated(_, ref frames)) = image.picture {
+                    if frames.advance(&glib::get_current_time()) {
+                        image.scaled = None;
+                        image.area.queue_draw();
+                    }
+                }
+            }
+            me.animate(&anchor);
+            Continue(false)
+        });
+    }
+    /// Make an icon to stand in for an image that couldn't be loaded
+    fn broken_image(&self) -> Option<Pixbuf> {
+        self.view
+            .render_icon_pixbuf("gtk-missing-image", IconSize::Dialog.to_glib() as i32)
+    }
+    /// The widest an image at `iter` can be shown without the view scrolling sideways
+    fn image_width(&self, iter: &TextIter) -> i32 {
+        let indent = self.containers_at(iter)
+            .iter()
+            .map(|&(_, depth, _)| depth as i32 * INDENT)
+            .fold(0, max);
+        let width = self.view.get_allocated_width() - self.view.get_left_margin() -
+                    self.view.get_right_margin() - indent;
+        // Before the view has been shown, it has no width to fit images to
+        if self.view.get_allocated_width() > 1 {
+            max(width, PLACEHOLDER_SIZE)
+        } else {
+            DEFAULT_IMAGE_WIDTH
+        }
+    }
+    /// Fit the area showing an image to the size the image should be shown at
+    fn resize_image(&self, anchor: &TextChildAnchor) {
+        if anchor.get_deleted() {
+            return;
+        }
+        let max_width = self.image_width(&self.text.get_iter_at_child_anchor(anchor));
+        if let Some(image) = self.images.lock().unwrap().get(anchor) {
+            let (width, height) = image.size(max_width);
+            if image.area.get_size_request() != (width, height) {
+                image.area.set_size_request(width, height);
+            }
+            image.area.queue_draw();
+        }
+    }
+    /// Fit every image to the width of the view
+    fn reflow_images(&self) {
+        let anchors: Vec<TextChildAnchor> = self.images.lock().unwrap().keys().cloned().collect();
+        for anchor in anchors {
+            self.resize_image(&anchor);
+        }
+    }
+    /// Change how far an image is zoomed in, which leaves the document as it is
+    pub fn zoom_image(&self, anchor: &TextChildAnchor, zoom: f64) {
+        if let Some(image) = self.images.lock().unwrap().get_mut(anchor) {
+            image.zoom = zoom;
         }
+        self.resize_image(anchor);
+    }
+    /// Show the menu for zooming an image, or editing it
+    fn image_menu(&self, anchor: &TextChildAnchor, time: u32) {
+        let (zoom, is_html) = match self.images.lock().unwrap().get(anchor) {
+            Some(image) => (image.zoom, image.info.html.is_some()),
+            None => return,
+        };
+        let glade_src = include_str!("../image-menu.glade");
+        let builder = Builder::new_from_string(glade_src);
+        let menu: Menu = builder.get_object("menu").unwrap();
+        let zooms = [("zoom-in", zoom * ZOOM_STEP),
+                     ("zoom-out", zoom / ZOOM_STEP),
+                     ("zoom-reset", 1.)];
+        for &(id, zoom) in &zooms {
+            let item: MenuItem = builder.get_object(id).unwrap();
+            let me = self.clone();
+            let anchor = anchor.clone();
+            item.connect_activate(move |_| me.zoom_image(&anchor, zoom));
+        }
+        let edit_image: MenuItem = builder.get_object("edit-image").unwrap();
+        // Images written as HTML are saved as written, so can't be edited here
+        edit_image.set_sensitive(!is_html);
+        let me = self.clone();
+        let anchor = anchor.clone();
+        edit_image.connect_activate(move |_| me.edit_image(&anchor));
+        menu.popup_easy(3, time);
+    }
+    /// Insert an image pasted from the clipboard at the cursor, either embedding it or saving it
+    /// as an asset
+    pub fn paste_image(&self, pixbuf: &Pixbuf) {
+        let png = match util::encode_png(pixbuf) {
+            Some(png) => png,
+            None => return,
+        };
+        let url = match (*self.pasted_images.lock().unwrap(), &*self.source.lock().unwrap()) {
+            (PastedImages::Assets, &Source::File(ref path)) => {
+                match util::save_asset(path, "png", &png) {
+                    Ok(url) => url,
+                    Err(err) => {
+                        self.show_error(&format!("Couldn't save the pasted image: {}", err));
+                        return;
+                    }
+                }
+            }
+            _ => format!("data:image/png;base64,{}", base64::encode(&png)),
+        };
+        self.text.delete_selection(true, true);
+        let mut iter = self.text.get_iter_at_mark(&self.text.get_insert().unwrap());
+        self.add_image(&mut iter,
+                       ImageInfo {
+                           url,
+                           ..ImageInfo::default()
+                       },
+                       Some(Picture::Still(pixbuf.clone())));
+    }
+    /// Change the alt text and title of an image
+    pub fn set_image(&self, anchor: &TextChildAnchor, alt: &str, title: &str) {
+        if let Some(image) = self.images.lock().unwrap().get_mut(anchor) {
+            image.info.alt = alt.to_owned();
+            image.info.title = title.to_owned();
+            image.widget.set_tooltip_text(image.info.tooltip());
+            image.update_fallback();
+            self.text.set_modified(true);
+        }
+    }
+    /// Show the dialog for editing the alt text and title of an image, unless it is written as
+    /// HTML
+    fn edit_image(&self, anchor: &TextChildAnchor) {
+        let image = match self.images.lock().unwrap().get(anchor) {
+            Some(image) if image.info.html.is_none() => image.info.clone(),
+            _ => return,
+        };
+        let me = self.clone();
+        let anchor = anchor.clone();
+        image_dialog(Some(&image),
+                     move |_, alt, title| me.set_image(&anchor, &alt, &title));
     }
     pub fn setup(&self, app: &App) {
         self.window.add(&self.view);
         let event_box = EventBox::new();
         event_box.add(&self.label);
-        let tags = app.tags.clone();
+        // Ctrl-clicking a link opens it, while clicking it shows a popover for it
+        let me = self.clone();
         self.view
             .connect_button_press_event(move |text, ev| {
-                let (x, y) = ev.get_position();
-                if let Some(mut iter) = text.get_iter_at_location(x as i32, y as i32) {
-                    if iter.has_tag(&tags.lookup("link").unwrap()) {
-                        let mut marks;
-                        loop {
-                            marks = iter.get_marks();
-                            if marks.len() != 0 || !iter.backward_char() {
-                                break;
-                            }
+                if ev.get_button() == 1 && ev.get_state().contains(gdk::CONTROL_MASK) {
+                    let (x, y) = ev.get_position();
+                    let (x, y) = text.window_to_buffer_coords(TextWindowType::Text,
+                                                              x as i32,
+                                                              y as i32);
+                    if let Some(iter) = text.get_iter_at_location(x, y) {
+                        if let Some(mark) = me.link_containing(&iter) {
+                            let url = me.links.lock().unwrap()[&mark].url.clone();
+                            me.open_link(&url);
+                            return Inhibit(true);
                         }
-                        if let Some(url) = marks.get(0).and_then(TextMark::get_name) {
-                            webbrowser::open(&url)
-                                .ok()
-                                .expect("Failed to open web browser");
+                    }
+                }
+                Inhibit(false)
+            });
+        let me = self.clone();
+        self.view
+            .connect_button_release_event(move |text, ev| {
+                // Not when selecting text
+                if ev.get_button() == 1 && !me.text.get_has_selection() {
+                    let (x, y) = ev.get_position();
+                    let (x, y) = text.window_to_buffer_coords(TextWindowType::Text,
+                                                              x as i32,
+                                                              y as i32);
+                    if let Some(iter) = text.get_iter_at_location(x, y) {
+                        if let Some(mark) = me.link_containing(&iter) {
+                            me.link_popover(&mark);
                         }
                     }
                 }
                 Inhibit(false)
             });
+        // Images on the clipboard are pasted as images, unless there is text to paste instead
+        let me = self.clone();
+        self.view
+            .connect_paste_clipboard(move |text| {
+                let clipboard = Clipboard::get(&gdk::Atom::from("CLIPBOARD"));
+                if !clipboard.wait_is_text_available() {
+                    if let Some(pixbuf) = clipboard.wait_for_image() {
+                        me.paste_image(&pixbuf);
+                        glib::signal_stop_emission_by_name(text, "paste-clipboard");
+                    }
+                }
+            });
+        // Images shrink to fit the view when it gets narrower, and grow back when it gets wider
+        let me = self.clone();
+        let width = Rc::new(Cell::new(0));
+        self.view
+            .connect_size_allocate(move |_, allocation| if allocation.width != width.get() {
+                                       width.set(allocation.width);
+                                       me.reflow_images();
+                                   });
         let app2 = app.clone();
         let source = self.source.clone();
+        let pasted_images = self.pasted_images.clone();
+        let backup = self.backup.clone();
+        let view = self.clone();
         event_box.connect_button_press_event(move |me, ev| {
             if ev.get_button() == 3 {
                 // Load menu
@@ -113,6 +1146,45 @@ impl View {
                 let builder = Builder::new_from_string(glade_src);
                 let menu: Menu = builder.get_object("menu").unwrap();
                 let close_tab: MenuItem = builder.get_object("close-tab").unwrap();
+                let embed_images: CheckMenuItem = builder.get_object("embed-images").unwrap();
+                let keep_backup: CheckMenuItem = builder.get_object("keep-backup").unwrap();
+                let lock: CheckMenuItem = builder.get_object("lock").unwrap();
+                // Only files have somewhere to keep assets
+                let is_file = match *source.lock().unwrap() {
+                    Source::File(_) => true,
+                    _ => false,
+                };
+                embed_images.set_sensitive(is_file);
+                embed_images.set_active(*pasted_images.lock().unwrap() == PastedImages::Embed);
+                let pasted_images = pasted_images.clone();
+                embed_images.connect_toggled(move |item| {
+                    *pasted_images.lock().unwrap() = if item.get_active() {
+                        PastedImages::Embed
+                    } else {
+                        PastedImages::Assets
+                    };
+                });
+                // Only files keep backups
+                keep_backup.set_sensitive(is_file);
+                keep_backup.set_active(*backup.lock().unwrap());
+                let backup = backup.clone();
+                keep_backup.connect_toggled(move |item| {
+                                                *backup.lock().unwrap() = item.get_active();
+                                            });
+                // Only documents on servers can be locked
+                let is_url = match *source.lock().unwrap() {
+                    Source::Url(_) => true,
+                    _ => false,
+                };
+                lock.set_sensitive(is_url);
+                lock.set_active(view.remote.lock().unwrap().lock.is_some());
+                let view = view.clone();
+                lock.connect_toggled(move |item| {
+                    if let Err(err) = view.set_locked(item.get_active()) {
+                        view.show_error(&format!("Couldn't change the lock on the document: {}",
+                                                 err));
+                    }
+                });
                 let app = app2.clone();
                 let source = source.clone();
                 close_tab.connect_activate(move |_| {
@@ -125,7 +1197,7 @@ impl View {
                                          *v.source.lock().unwrap() == *source.lock().unwrap()
                                      })
                                .map(|(i, _)| i) {
-                            views.remove(index);
+                            views.remove(index).release();
                             Some(index)
                         } else {
                             None
@@ -142,8 +1214,14 @@ impl View {
         });
         let tags = app.tags.clone();
         let me = self.clone();
+        let busy = Rc::new(Cell::new(false));
         self.text
             .connect_changed(move |text| {
+                // Edits made while handling a change would otherwise be handled again
+                if busy.get() {
+                    return;
+                }
+                busy.set(true);
                 let cursor = text.get_property_cursor_position();
                 let mut iter = text.get_iter_at_offset(cursor);
                 iter.backward_char();
@@ -155,143 +1233,349 @@ impl View {
                     iter.backward_line();
                     let mut true_start = iter.clone();
                     let ch = iter.get_char();
-                    match ch {
-                        Some('#') => {
-                            let mut start = iter.clone();
-                            let mut level = 1;
-                            while start.forward_char() && start.get_char() == Some('#') {
-                                level += 1;
+                    let line = text.get_slice(&iter, &end, false).unwrap_or_default();
+                    let in_code = iter.has_tag(&tags.lookup("code").unwrap());
+                    let in_list = !me.lists_at(&iter).is_empty();
+                    let in_quote = innermost_quote(&me.containers_at(&iter)).is_some();
+                    let prefix = if in_list || in_code {
+                        None
+                    } else {
+                        list_prefix(&line)
+                    };
+                    if in_list && iter.has_tag(&tags.lookup("item").unwrap()) {
+                        me.continue_item(iter.get_line());
+                    } else if let Some((start, length)) = prefix {
+                        me.start_list(iter.get_line(), start, length);
+                    } else if in_quote && line.is_empty() {
+                        me.leave_quote(iter.get_line());
+                    } else if !in_code && line.starts_with("> ") {
+                        me.start_quote(iter.get_line());
+                    } else {
+                        match ch {
+                            Some('#') if !in_code => {
+                                let mut start = iter.clone();
+                                let mut level = 1;
+                                while start.forward_char() && start.get_char() == Some('#') {
+                                    level += 1;
+                                }
+                                // Only one to six `#`s followed by a space make a heading
+                                if level <= 6 && start.get_char() == Some(' ') {
+                                    while start.get_char() == Some(' ') && start.forward_char() {}
+                                    me.line_tag(&tags.lookup(&format!("h{}", level)).unwrap(),
+                                                &start,
+                                                &end);
+                                    text.delete(&mut true_start, &mut start);
+                                }
+                            }
+                            Some(underline) if (underline == '=' || underline == '-') &&
+                                               !in_code => {
+                                // A line of `=` or `-` underlines the line above as a heading
+                                let mut above = iter.clone();
+                                if line.trim_right().chars().all(|c| c == underline) &&
+                                   above.backward_line() &&
+                                   !above.ends_line() &&
+                                   me.heading_at(&above).is_none() {
+                                    let level = if underline == '=' { 1 } else { 2 };
+                                    me.line_tag(&tags.lookup(&format!("h{}", level)).unwrap(),
+                                                &above,
+                                                &above);
+                                    // Remove the underline, keeping the new line just typed
+                                    let mut above_end = above.clone();
+                                    above_end.forward_to_line_end();
+                                    let mut end = end.clone();
+                                    text.delete(&mut above_end, &mut end);
+                                }
                             }
-                            while start.get_char() == Some(' ') && start.forward_char() {}
-                            me.line_tag(&tags.lookup(&format!("h{}", level)).unwrap(),
-                                        &start,
-                                        &end);
-                            text.delete(&mut true_start, &mut start);
+                            _ => (),
                         }
-                        _ => (),
                     }
                 }
+                busy.set(false);
             });
-        app.tabs.append_page(&self.window, Some(&event_box));
+        let me = self.clone();
+        self.view
+            .connect_key_press_event(move |_, ev| {
+                let key = ev.get_keyval();
+                let cursor = me.text.get_iter_at_mark(&me.text.get_insert().unwrap());
+                if key == key::BackSpace || key == key::Delete {
+                    return Inhibit(me.delete_rule(&cursor, key == key::BackSpace));
+                }
+                if key != key::Tab && key != key::ISO_Left_Tab || me.lists_at(&cursor).is_empty() {
+                    return Inhibit(false);
+                }
+                // Tab and Shift-Tab change the depth of list items
+                if key == key::Tab && !ev.get_state().contains(gdk::SHIFT_MASK) {
+                    me.indent_item(cursor.get_line());
+                } else {
+                    me.outdent_item(cursor.get_line());
+                }
+                Inhibit(true)
+            });
+        let app3 = app.clone();
+        let me = self.clone();
+        self.text
+            .connect_mark_set(move |_, _, mark| {
+                // Keep the heading dropdown in sync with the line the cursor is on
+                if mark.get_name().as_ref().map(String::as_str) == Some("insert") {
+                    app3.show_heading(me.heading());
+                }
+            });
+        app.tabs.append_page(&self.page, Some(&event_box));
         event_box.show_all();
         app.tabs.set_current_page(None);
-        self.window.show_all();
-    }
-
-    pub fn open(source: Source, tags: &TextTagTable) -> View {
-        let orig_text = source.load();
-        let view = View::new(source, tags);
-        let parser = Parser::new(&orig_text);
-        let mut post_ops: Vec<Box<Fn(&TextView, &TextBuffer, &[Option<Pixbuf>])>> =
-            Vec::with_capacity(4);
-        let mut tag_starts = Vec::with_capacity(4);
-        let mut tag_defs = Vec::new();
-        let mut row = 0;
-        let mut column = 0;
-        let mut text = String::with_capacity(orig_text.len());
-        let mut in_image = false;
-        let mut image_urls = Vec::new();
-        for event in parser {
-            println!("{:?}", event);
-            match event {
-                Event::HardBreak |
-                Event::End(Tag::Paragraph) => {
-                    text.push('\n');
-                    row += 1;
-                    column = 0;
-                }
-                Event::End(Tag::Rule) => {
-                    let (sep_row, sep_column) = (row, column);
-                    post_ops.push(Box::new(move |view, buf, _| {
-                        let mut iter = buf.get_iter_at_line_index(sep_row, sep_column);
-                        if let Some(anchor) = buf.create_child_anchor(&mut iter) {
-                            let sep = Separator::new(Orientation::Horizontal);
-                            view.add_child_at_anchor(&sep, &anchor);
-                        }
+        self.page.show_all();
+    }
 
-                    }));
+    /// Load the document from its source into the view, before it is set up
+    pub fn load(&self) -> Result<(), Error> {
+        let credentials = self.remote.lock().unwrap().credentials.clone();
+        let loaded = self.source.lock().unwrap().load(credentials);
+        // An empty tab left by a failed load would wipe out the document if saved
+        self.remote.lock().unwrap().unloaded = loaded.is_err();
+        let loaded = loaded?;
+        let orig_text = loaded.text;
+        *self.remote.lock().unwrap() = loaded.remote;
+        *self.format.lock().unwrap() = loaded.format;
+        // The document is saved back to, and shown as, where it was found in the end
+        if let Some(url) = loaded.moved {
+            *self.source.lock().unwrap() = Source::Url(url);
+        }
+        let mut document = Document::parse(&orig_text);
+        document.collect_footnotes();
+        *self.definitions.lock().unwrap() = document.definitions.clone();
+        self.render_blocks(&document.blocks, 0);
+        self.text.set_modified(false);
+        *self.original.lock().unwrap() = Some(Original {
+                                                  text: orig_text,
+                                                  document: self.document(),
+                                              });
+        Ok(())
+    }
+    /// Take or release a WebDAV lock on the document on its server
+    pub fn set_locked(&self, locked: bool) -> Result<(), Error> {
+        let source = self.source.lock().unwrap();
+        let mut remote = self.remote.lock().unwrap();
+        if locked && remote.lock.is_none() {
+            remote.lock = source.lock(remote.credentials.as_ref())?;
+        } else if !locked {
+            if let Some(token) = remote.lock.take() {
+                source.unlock(&token, remote.credentials.as_ref())?;
+            }
+        }
+        Ok(())
+    }
+    /// Let go of anything held on the document's behalf, before its tab is closed
+    pub fn release(&self) {
+        // The lock runs out by itself if the server can't be told
+        self.set_locked(false).ok();
+    }
+    /// Save the document in another encoding from now on, which leaves it to be saved
+    pub fn set_encoding(&self, encoding: Encoding) {
+        let mut format = self.format.lock().unwrap();
+        if format.encoding != encoding {
+            format.encoding = encoding;
+            // UTF-16 can't be told apart from other encodings without a byte order mark, while
+            // UTF-8 is better off without one
+            format.bom = encoding == Encoding::Utf16Le || encoding == Encoding::Utf16Be;
+            self.text.set_modified(true);
+        }
+    }
+    /// Show what went wrong with the document in the bar above it
+    pub fn show_error(&self, message: &str) {
+        self.message.set_text(message);
+        self.info_bar.show();
+    }
+    /// Open a link in the web browser, if there is one to open it in
+    fn open_link(&self, url: &str) {
+        match webbrowser::open(url) {
+            Ok(ref output) if output.status.success() => (),
+            // The opener can be there without any browser for it to open
+            Ok(_) => self.show_error(&format!("Couldn't find a web browser to open {} in", url)),
+            Err(err) => self.show_error(&format!("Couldn't open {}: {}", url, err)),
+        }
+    }
+    /// Insert text at the end of the buffer with the tags given
+    fn insert(&self, text: &str, tags: &[&str]) {
+        let offset = self.text.get_end_iter().get_offset();
+        let mut end = self.text.get_end_iter();
+        self.text.insert(&mut end, text);
+        self.tag_from(offset, tags);
+    }
+    /// Apply tags from an offset to the end of the buffer
+    fn tag_from(&self, offset: i32, tags: &[&str]) {
+        let start = self.text.get_iter_at_offset(offset);
+        let end = self.text.get_end_iter();
+        for tag in tags {
+            self.text.apply_tag_by_name(tag, &start, &end);
+        }
+    }
+    /// Render blocks nested `depth` lists deep
+    fn render_blocks(&self, blocks: &[Block], depth: usize) {
+        for block in blocks {
+            self.render_block(block, depth);
+        }
+    }
+    fn render_block(&self, block: &Block, depth: usize) {
+        match *block {
+            Block::Paragraph(ref inlines) => {
+                self.render_inlines(inlines, &[]);
+                self.insert("\n", &[]);
+            }
+            Block::Header(level, ref inlines) => {
+                let name = format!("h{}", min(max(level, 1), 6));
+                self.render_inlines(inlines, &[name.as_str()]);
+                self.insert("\n", &[]);
+            }
+            Block::CodeBlock { ref info, ref text } => {
+                let offset = self.text.get_end_iter().get_offset();
+                // Leave the last newline out of the block so typing after it leaves the block
+                let text = if text.ends_with('\n') {
+                    &text[..text.len() - 1]
+                } else {
+                    &text[..]
+                };
+                self.insert(text, &[]);
+                let start = self.text.get_iter_at_offset(offset);
+                self.code_block(info, &start, &self.text.get_end_iter());
+                self.insert("\n", &[]);
+            }
+            Block::Html(ref html) => {
+                // A block that is just an image is shown as one, on a line of its own
+                match ImageInfo::from_html(html) {
+                    Some(image) => self.insert_image(&mut self.text.get_end_iter(), image),
+                    None => self.insert(html.trim_right_matches('\n'), &["html-block"]),
                 }
-                Event::Start(tag) => {
-                    match tag {
-                        Tag::Image(_, _) => {
-                            in_image = true;
-                        }
-                        Tag::Item => {
-                            text.push_str("• ");
-                            column += 2;
-                        }
-                        _ => (),
-                    }
-                    tag_starts.push((tag, row, column))
-                }
-                Event::End(Tag::Image(ref url, _)) => {
-                    let url = Url::parse(&url).unwrap();
-                    let (img_row, img_col) = (row, column);
-                    let index = image_urls.len();
-                    image_urls.push(url.clone());
-                    let me_image_urls = view.image_urls.clone();
-                    post_ops.push(Box::new(move |_, buf, pixbufs| {
-                        let mut actual_image_urls = me_image_urls.lock().unwrap();
-                        // If the image has loaded
-                        if let Some(ref pixbuf) = pixbufs[index] {
-                            actual_image_urls.insert(pixbuf.clone(), url.clone());
-                            let mut place = buf.get_iter_at_line_index(img_row, img_col);
-                            buf.insert_pixbuf(&mut place, pixbuf);
-                        }
-                    }));
-                    in_image = false;
+                self.insert("\n", &[]);
+            }
+            Block::Rule => {
+                self.rule(&mut self.text.get_end_iter());
+                self.insert("\n", &[]);
+            }
+            Block::Table {
+                ref alignments,
+                ref rows,
+            } => {
+                let mut iter = self.text.get_end_iter();
+                if let Some(anchor) = self.text.create_child_anchor(&mut iter) {
+                    let (grid, table) = self.table(alignments, rows);
+                    self.view.add_child_at_anchor(&grid, &anchor);
+                    self.tables.lock().unwrap().insert(anchor, table);
                 }
-                Event::End(_) => {
-                    let (tag, start_row, start_column) = tag_starts.pop().unwrap();
-                    if is_block(&tag) {
-                        text.push('\n');
+                self.insert("\n", &[]);
+            }
+            Block::FootnoteDefinition {
+                ref label,
+                ref blocks,
+            } => {
+                let offset = self.text.get_end_iter().get_offset();
+                let tag = self.footnote_definition(depth + 1, label);
+                if blocks.is_empty() {
+                    self.insert("\n", &[]);
+                }
+                self.render_blocks(blocks, depth + 1);
+                let start = self.text.get_iter_at_offset(offset);
+                let end = self.text.get_end_iter();
+                self.text.apply_tag(&tag, &start, &end);
+                self.text.apply_tag_by_name("footnote", &start, &end);
+            }
+            Block::BlockQuote(ref blocks) => {
+                let tag = self.quote_tag(depth + 1);
+                let offset = self.text.get_end_iter().get_offset();
+                if blocks.is_empty() {
+                    self.insert("\n", &[]);
+                }
+                self.render_blocks(blocks, depth + 1);
+                let start = self.text.get_iter_at_offset(offset);
+                let end = self.text.get_end_iter();
+                self.text.apply_tag(&tag, &start, &end);
+                self.text.apply_tag_by_name("blockquote", &start, &end);
+            }
+            Block::List {
+                start,
+                tight,
+                ref items,
+            } => {
+                let tag = self.list_tag(depth + 1, start, tight);
+                for (i, item) in items.iter().enumerate() {
+                    let offset = self.text.get_end_iter().get_offset();
+                    self.insert(&list_marker(start, i), &["item"]);
+                    if item.is_empty() {
+                        self.insert("\n", &[]);
                     }
-                    let name = match tag {
-                        Tag::Code |
-                        Tag::CodeBlock(_) => Some("code"),
-                        Tag::Item => Some("item"),
-                        Tag::Header(n) => {
-                            column = 0;
-                            row += 1;
-                            Some(match n {
-                                     1 => "h1",
-                                     2 => "h2",
-                                     _ => "h3",
-                                 })
-                        }
-                        Tag::Link(url, _) => {
-                            let (end_row, end_col) = (row, column);
-                            post_ops.push(Box::new(move |_, buf, _| {
-                                let start = buf.get_iter_at_line_index(start_row, start_column);
-                                let end = buf.get_iter_at_line_index(end_row, end_col);
-                                buf.apply_tag_by_name("link", &start, &end);
-                                let url = url.to_string();
-                                let mark = TextMark::new(url.as_str(), false);
-                                buf.add_mark(&mark, &start);
-                            }));
-                            Some("link")
-                        }
-                        Tag::Strong => Some("bold"),
-                        Tag::Emphasis => Some("italic"),
-                        _ => None,
-                    };
-                    if let Some(name) = name {
-                        tag_defs.push((name, start_row, start_column, row, column));
+                    self.render_blocks(item, depth + 1);
+                    let start = self.text.get_iter_at_offset(offset);
+                    self.text.apply_tag(&tag, &start, &self.text.get_end_iter());
+                }
+            }
+        }
+    }
+    fn render_inlines(&self, inlines: &[Inline], tags: &[&str]) {
+        for inline in inlines {
+            let offset = self.text.get_end_iter().get_offset();
+            match *inline {
+                Inline::Text(ref text) => self.insert(text, tags),
+                Inline::Html(ref html) => {
+                    match ImageInfo::from_html(html) {
+                        Some(image) => self.insert_image(&mut self.text.get_end_iter(), image),
+                        None => self.insert(html, &with_tag(tags, "html")),
                     }
                 }
-                Event::Text(ref etext) if !in_image => {
-                    text.push_str(&**etext);
-                    column += etext.len() as i32
+                Inline::Code(ref code) => self.insert(code, &with_tag(tags, "code")),
+                Inline::Emphasis(ref content) => {
+                    self.render_inlines(content, &with_tag(tags, "italic"))
+                }
+                Inline::Strong(ref content) => {
+                    self.render_inlines(content, &with_tag(tags, "bold"))
+                }
+                Inline::Strikethrough(ref content) => {
+                    self.render_inlines(content, &with_tag(tags, "strikethrough"))
                 }
-                _ => (),
+                Inline::TaskMarker(checked) => self.task(&mut self.text.get_end_iter(), checked),
+                Inline::FootnoteReference(ref label) => {
+                    self.footnote_reference(&mut self.text.get_end_iter(), label)
+                }
+                Inline::Link {
+                    ref url,
+                    ref title,
+                    ref content,
+                    ref reference,
+                } => {
+                    let offset = self.text.get_end_iter().get_offset();
+                    self.render_inlines(content, &with_tag(tags, "link"));
+                    let start = self.text.get_iter_at_offset(offset);
+                    let mark = TextMark::new(None, false);
+                    self.text.add_mark(&mark, &start);
+                    self.links
+                        .lock()
+                        .unwrap()
+                        .insert(mark,
+                                LinkInfo {
+                                    url: url.clone(),
+                                    title: title.clone(),
+                                    reference: reference.clone(),
+                                });
+                }
+                Inline::Image {
+                    ref url,
+                    ref title,
+                    ref alt,
+                    ref reference,
+                } => {
+                    self.insert_image(&mut self.text.get_end_iter(),
+                                      ImageInfo {
+                                          url: url.clone(),
+                                          alt: alt.clone(),
+                                          title: title.clone(),
+                                          reference: reference.clone(),
+                                          ..ImageInfo::default()
+                                      });
+                }
+                Inline::SoftBreak => self.insert("\n", &with_tag(tags, "soft-break")),
+                Inline::HardBreak => self.insert("\n", &with_tag(tags, "hard-break")),
             }
+            // Images and other widgets take the styles around them, like the link they are in
+            self.tag_from(offset, tags);
         }
-        let pixbufs = util::load_pixbufs(&image_urls, 500);
-        view.text.set_text(&text);
-        for op in post_ops {
-            op(&view.view, &view.text, pixbufs.as_slice());
-        }
-        view
     }
     pub fn apply_line_tag(&self, tag: &TextTag) {
         if let Some((start, end)) = self.text.get_selection_bounds() {
@@ -300,10 +1584,11 @@ impl View {
     }
     pub fn line_tag(&self, tag: &TextTag, start: &TextIter, end: &TextIter) {
         let mut line_end = end.clone();
-        line_end.forward_line();
-        line_end.backward_char();
+        if !line_end.ends_line() {
+            line_end.forward_to_line_end();
+        }
         let mut line_start = start.clone();
-        line_start.backward_line();
+        line_start.set_line_offset(0);
         let mut iter = line_start.clone();
         if iter.forward_to_tag_toggle(Some(tag)) && iter <= line_end {
             self.text.remove_tag(tag, &line_start, &line_end);
@@ -311,6 +1596,550 @@ impl View {
             self.text.apply_tag(tag, &line_start, &line_end);
         }
     }
+    /// Insert a rule at `iter`, leaving it after the rule
+    fn rule(&self, iter: &mut TextIter) {
+        if let Some(anchor) = self.text.create_child_anchor(iter) {
+            let separator = Separator::new(Orientation::Horizontal);
+            self.view.add_child_at_anchor(&separator, &anchor);
+            separator.show();
+            self.rules.lock().unwrap().insert(anchor);
+        }
+    }
+    /// Insert the checkbox of a task item at `iter`, leaving it after the checkbox
+    fn task(&self, iter: &mut TextIter, checked: bool) {
+        if let Some(anchor) = self.text.create_child_anchor(iter) {
+            let check = CheckButton::new();
+            check.set_active(checked);
+            let text = self.text.clone();
+            check.connect_toggled(move |_| text.set_modified(true));
+            self.view.add_child_at_anchor(&check, &anchor);
+            check.show();
+            self.tasks.lock().unwrap().insert(anchor, check);
+        }
+    }
+    /// Insert a superscript marker showing a footnote label at `iter`, leaving it after the marker
+    fn footnote_marker(&self,
+                       iter: &mut TextIter,
+                       label: &str)
+                       -> Option<(TextChildAnchor, Label)> {
+        let anchor = match self.text.create_child_anchor(iter) {
+            Some(anchor) => anchor,
+            None => return None,
+        };
+        let marker = Label::new(None);
+        set_footnote_label(&marker, label);
+        self.view.add_child_at_anchor(&marker, &anchor);
+        marker.show();
+        Some((anchor, marker))
+    }
+    /// Insert a reference to a footnote at `iter`, showing its definition when hovered over
+    fn footnote_reference(&self, iter: &mut TextIter, label: &str) {
+        if let Some((anchor, marker)) = self.footnote_marker(iter, label) {
+            marker.set_has_tooltip(true);
+            let me = self.clone();
+            marker.connect_query_tooltip(move |marker, _, _, _, tooltip| {
+                let label = marker.get_text().unwrap_or_default();
+                match me.footnote_text(&label) {
+                    Some(text) => {
+                        tooltip.set_text(Some(text.as_str()));
+                        true
+                    }
+                    None => false,
+                }
+            });
+            self.footnote_refs.lock().unwrap().insert(anchor, marker);
+        }
+    }
+    /// Start the definition of a footnote at the end of the buffer, returning the tag to
+    /// mark its lines with
+    fn footnote_definition(&self, depth: usize, label: &str) -> TextTag {
+        let tag = TextTag::new(None);
+        tag.set_property_left_margin(INDENT * (depth as i32 - 1));
+        self.text.get_tag_table().unwrap().add(&tag);
+        if let Some((_, marker)) = self.footnote_marker(&mut self.text.get_end_iter(), label) {
+            let mut footnotes = self.footnotes.lock().unwrap();
+            let id = footnotes.len();
+            footnotes.insert(tag.clone(), FootnoteInfo { id, depth, marker });
+        }
+        tag
+    }
+    /// Get the text of the definition of the footnote labelled `label`
+    pub fn footnote_text(&self, label: &str) -> Option<String> {
+        let tag = self.footnotes
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|&(_, info)| info.marker.get_text().as_ref().map(String::as_str) == Some(label))
+            .map(|(tag, _)| tag.clone());
+        let tag = match tag {
+            Some(tag) => tag,
+            None => return None,
+        };
+        let mut start = self.text.get_start_iter();
+        if !start.has_tag(&tag) && !start.forward_to_tag_toggle(Some(&tag)) {
+            return None;
+        }
+        let mut end = start.clone();
+        end.forward_to_tag_toggle(Some(&tag));
+        self.text
+            .get_text(&start, &end, false)
+            .map(|text| text.trim().to_owned())
+    }
+    /// Insert a reference to a new footnote at the cursor, adding its definition to the end
+    pub fn insert_footnote(&self) {
+        let label = self.document().next_footnote_label();
+        let mut iter = self.text.get_iter_at_mark(&self.text.get_insert().unwrap());
+        self.footnote_reference(&mut iter, &label);
+        let mut end = self.text.get_end_iter();
+        if !end.starts_line() {
+            self.text.insert(&mut end, "\n");
+        }
+        let offset = self.text.get_end_iter().get_offset();
+        let tag = self.footnote_definition(1, &label);
+        self.insert("\n", &[]);
+        let start = self.text.get_iter_at_offset(offset);
+        let end = self.text.get_end_iter();
+        self.text.apply_tag(&tag, &start, &end);
+        self.text.apply_tag_by_name("footnote", &start, &end);
+        // Leave the cursor ready to write the definition
+        self.text.place_cursor(&self.text.get_iter_at_offset(offset + 1));
+    }
+    /// Relabel the footnotes shown after their labels have been changed
+    fn rename_footnotes(&self, renamed: &HashMap<String, String>) {
+        let footnotes = self.footnotes.lock().unwrap();
+        let footnote_refs = self.footnote_refs.lock().unwrap();
+        let markers = footnotes
+            .values()
+            .map(|info| &info.marker)
+            .chain(footnote_refs.values());
+        for marker in markers {
+            if let Some(label) = marker.get_text().and_then(|label| renamed.get(&label)) {
+                set_footnote_label(marker, label);
+            }
+        }
+    }
+    /// Check if `iter` is at the checkbox of a task item
+    fn is_task(&self, iter: &TextIter) -> bool {
+        iter.get_child_anchor()
+            .map_or(false, |anchor| self.tasks.lock().unwrap().contains_key(&anchor))
+    }
+    /// Insert a rule on a line of its own at the cursor
+    pub fn insert_rule(&self) {
+        let mut iter = self.text.get_iter_at_mark(&self.text.get_insert().unwrap());
+        if !iter.starts_line() {
+            self.text.insert(&mut iter, "\n");
+        }
+        self.rule(&mut iter);
+        self.text.insert(&mut iter, "\n");
+        self.text.place_cursor(&iter);
+    }
+    /// Check if the line `iter` is on starts with a rule
+    fn is_rule(&self, iter: &TextIter) -> bool {
+        let mut line_start = iter.clone();
+        line_start.set_line_offset(0);
+        line_start
+            .get_child_anchor()
+            .map_or(false, |anchor| self.rules.lock().unwrap().contains(&anchor))
+    }
+    /// Delete the rule a key press at `cursor` would delete part of, along with its line,
+    /// returning whether there was one
+    fn delete_rule(&self, cursor: &TextIter, backward: bool) -> bool {
+        if self.text.get_selection_bounds().is_some() {
+            return false;
+        }
+        let line = if backward && cursor.starts_line() {
+            let mut above = cursor.clone();
+            if !above.backward_line() {
+                return false;
+            }
+            above
+        } else if backward && cursor.get_line_offset() == 1 || !backward && cursor.starts_line() {
+            cursor.clone()
+        } else {
+            return false;
+        };
+        if !self.is_rule(&line) {
+            return false;
+        }
+        let (mut start, mut end) = line_bounds(&line);
+        self.text.delete(&mut start, &mut end);
+        true
+    }
+    /// Create a grid of entries for editing the cells of a table
+    fn table(&self, alignments: &[Alignment], rows: &[Vec<Vec<Inline>>]) -> (Grid, Table) {
+        let grid = Grid::new();
+        grid.set_column_spacing(2);
+        grid.set_row_spacing(2);
+        let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+        let widths: Vec<usize> = (0..columns)
+            .map(|column| {
+                rows.iter()
+                    .filter_map(|row| row.get(column))
+                    .map(|cell| document::plain_text(cell).chars().count())
+                    .fold(3, max)
+            })
+            .collect();
+        let cells = rows.iter()
+            .enumerate()
+            .map(|(row, cells)| {
+                widths
+                    .iter()
+                    .enumerate()
+                    .map(|(column, &width)| {
+                        let content = cells.get(column).cloned().unwrap_or_default();
+                        let entry = Entry::new();
+                        entry.set_text(&document::plain_text(&content));
+                        entry.set_alignment(match alignments.get(column) {
+                                                Some(&Alignment::Center) => 0.5,
+                                                Some(&Alignment::Right) => 1.,
+                                                _ => 0.,
+                                            });
+                        entry.set_width_chars(width as i32);
+                        let text = self.text.clone();
+                        entry.connect_changed(move |_| text.set_modified(true));
+                        grid.attach(&entry, column as i32, row as i32, 1, 1);
+                        (entry, content)
+                    })
+                    .collect()
+            })
+            .collect();
+        let table = Table {
+            alignments: alignments.to_vec(),
+            cells,
+        };
+        (grid, table)
+    }
+    /// Create the tag marking the lines of a new list
+    pub fn list_tag(&self, depth: usize, start: Option<usize>, tight: bool) -> TextTag {
+        let tag = TextTag::new(None);
+        tag.set_property_left_margin(INDENT * depth as i32);
+        if !tight {
+            tag.set_property_pixels_below_lines(LOOSE_LIST_SPACING);
+        }
+        self.text.get_tag_table().unwrap().add(&tag);
+        let mut lists = self.lists.lock().unwrap();
+        let id = lists.len();
+        lists.insert(tag.clone(),
+                     ListInfo {
+                         id,
+                         depth,
+                         start,
+                         tight,
+                     });
+        tag
+    }
+    /// Create the tag marking the lines of a new block quote
+    pub fn quote_tag(&self, depth: usize) -> TextTag {
+        let tag = TextTag::new(None);
+        tag.set_property_left_margin(INDENT * depth as i32);
+        self.text.get_tag_table().unwrap().add(&tag);
+        let mut quotes = self.quotes.lock().unwrap();
+        let id = quotes.len();
+        quotes.insert(tag.clone(), QuoteInfo { id, depth });
+        tag
+    }
+    /// Get the lists and quotes the line `iter` is on belongs to, outermost first
+    pub fn containers_at(&self, iter: &TextIter) -> Vec<(TextTag, usize, Container)> {
+        let lists = self.lists.lock().unwrap();
+        let quotes = self.quotes.lock().unwrap();
+        let footnotes = self.footnotes.lock().unwrap();
+        // A container can start after a list item's marker, so the line's end is checked too
+        let (line_start, mut line_end) = line_bounds(iter);
+        if line_end.get_line() != line_start.get_line() {
+            line_end.backward_char();
+        }
+        let mut found: Vec<(TextTag, usize, Container)> = Vec::new();
+        for tag in line_start.get_tags().into_iter().chain(line_end.get_tags()) {
+            if found.iter().any(|&(ref other, _, _)| *other == tag) {
+                continue;
+            }
+            if let Some(info) = lists.get(&tag) {
+                found.push((tag.clone(), info.depth, info.container()));
+            } else if let Some(info) = quotes.get(&tag) {
+                found.push((tag.clone(), info.depth, Container::BlockQuote { id: info.id }));
+            } else if let Some(info) = footnotes.get(&tag) {
+                found.push((tag.clone(), info.depth, info.container()));
+            }
+        }
+        found.sort_by_key(|&(_, depth, _)| depth);
+        found
+    }
+    /// Toggle whether the selected lines are quoted
+    pub fn toggle_quote(&self) {
+        let (start, end) = match self.text.get_selection_bounds() {
+            Some(bounds) => bounds,
+            None => {
+                let cursor = self.text.get_iter_at_mark(&self.text.get_insert().unwrap());
+                (cursor.clone(), cursor)
+            }
+        };
+        if innermost_quote(&self.containers_at(&start)).is_some() {
+            self.unquote_lines(start.get_line(), end.get_line());
+        } else {
+            self.quote_lines(start.get_line(), end.get_line());
+        }
+    }
+    /// Wrap the lines from `first` to `last` in a new quote, nested in any they're already in
+    pub fn quote_lines(&self, first: i32, last: i32) {
+        let depth = self.containers_at(&self.text.get_iter_at_line(first)).len();
+        let tag = self.quote_tag(depth + 1);
+        let (start, _) = line_bounds(&self.text.get_iter_at_line(first));
+        let (_, end) = line_bounds(&self.text.get_iter_at_line(last));
+        self.text.apply_tag(&tag, &start, &end);
+        self.text.apply_tag_by_name("blockquote", &start, &end);
+    }
+    /// Take the lines from `first` to `last` out of their innermost quote
+    pub fn unquote_lines(&self, first: i32, last: i32) {
+        for line in first..last + 1 {
+            let (start, end) = line_bounds(&self.text.get_iter_at_line(line));
+            let containers = self.containers_at(&start);
+            if let Some(tag) = innermost_quote(&containers) {
+                self.text.remove_tag(&tag, &start, &end);
+            }
+            // Lines left in no quote at all lose the quote styling
+            if innermost_quote(&self.containers_at(&start)).is_none() {
+                self.text.remove_tag_by_name("blockquote", &start, &end);
+            }
+        }
+    }
+    /// Quote the line typed with Markdown quote markers, continuing the quote onto the line after
+    fn start_quote(&self, line: i32) {
+        let mut iter = self.text.get_iter_at_line(line);
+        let mut prefix_end = iter.clone();
+        let mut depth = 0;
+        while self.text.get_slice(&prefix_end, &self.text.get_end_iter(), false)
+                  .map_or(false, |rest| rest.starts_with("> ")) {
+            prefix_end.forward_chars(2);
+            depth += 1;
+        }
+        self.text.delete(&mut iter, &mut prefix_end);
+        for _ in 0..depth {
+            self.quote_lines(line, line + 1);
+        }
+    }
+    /// Handle a new line typed after the empty quoted line `line` by leaving the quote instead
+    fn leave_quote(&self, line: i32) {
+        let mut start = self.text.get_iter_at_line(line);
+        if !start.ends_line() {
+            start.forward_to_line_end();
+        }
+        let mut end = start.clone();
+        end.forward_char();
+        self.text.delete(&mut start, &mut end);
+        self.unquote_lines(line, line);
+    }
+    /// Get the lists the line `iter` is on belongs to, outermost first
+    pub fn lists_at(&self, iter: &TextIter) -> Vec<(TextTag, ListInfo)> {
+        let lists = self.lists.lock().unwrap();
+        let mut line_start = iter.clone();
+        line_start.set_line_offset(0);
+        let mut found: Vec<(TextTag, ListInfo)> = line_start
+            .get_tags()
+            .into_iter()
+            .filter_map(|tag| lists.get(&tag).cloned().map(|info| (tag, info)))
+            .collect();
+        found.sort_by_key(|&(_, ref info)| info.depth);
+        found
+    }
+    /// Find the end of the item marker at the start of the line `iter` is on
+    fn marker_end(&self, iter: &TextIter) -> TextIter {
+        let item = self.text.get_tag_table().unwrap().lookup("item").unwrap();
+        let mut end = iter.clone();
+        end.set_line_offset(0);
+        while end.has_tag(&item) && !end.ends_line() {
+            end.forward_char();
+        }
+        end
+    }
+    fn insert_marker(&self, line: i32, marker: &str, lists: &[(TextTag, ListInfo)]) {
+        let mut iter = self.text.get_iter_at_line(line);
+        let offset = iter.get_offset();
+        self.text.insert(&mut iter, marker);
+        let start = self.text.get_iter_at_offset(offset);
+        self.text.apply_tag_by_name("item", &start, &iter);
+        for &(ref tag, _) in lists {
+            self.text.apply_tag(tag, &start, &iter);
+        }
+    }
+    /// Rewrite the item markers of a list, numbering ordered lists up from their start
+    fn update_markers(&self, tag: &TextTag) {
+        let start = match self.lists.lock().unwrap().get(tag) {
+            Some(info) => info.start,
+            None => return,
+        };
+        let item = self.text.get_tag_table().unwrap().lookup("item").unwrap();
+        let mut index = 0;
+        for line in 0..self.text.get_line_count() {
+            let mut iter = self.text.get_iter_at_line(line);
+            let lists = self.lists_at(&iter);
+            if lists.last().map(|&(ref innermost, _)| innermost) != Some(tag) ||
+               !iter.has_tag(&item) {
+                continue;
+            }
+            let marker = list_marker(start, index);
+            index += 1;
+            let mut marker_end = self.marker_end(&iter);
+            if self.text.get_slice(&iter, &marker_end, false).as_ref() != Some(&marker) {
+                self.text.delete(&mut iter, &mut marker_end);
+                self.insert_marker(line, &marker, &lists);
+            }
+        }
+    }
+    /// Turn the line typed with a Markdown list marker `length` characters long into a list,
+    /// continuing it onto the line after
+    fn start_list(&self, line: i32, start: Option<usize>, length: usize) {
+        let mut iter = self.text.get_iter_at_line(line);
+        let mut prefix_end = iter.clone();
+        prefix_end.forward_chars(length as i32);
+        self.text.delete(&mut iter, &mut prefix_end);
+        let depth = self.containers_at(&iter).len();
+        let tag = self.list_tag(depth + 1, start, true);
+        for line in line..line + 2 {
+            let (line_start, line_end) = line_bounds(&self.text.get_iter_at_line(line));
+            self.text.apply_tag(&tag, &line_start, &line_end);
+            let lists = self.lists_at(&line_start);
+            self.insert_marker(line, &list_marker(start, 0), &lists);
+        }
+        self.update_markers(&tag);
+    }
+    /// Handle a new line typed after the list item on `line`
+    fn continue_item(&self, line: i32) {
+        let iter = self.text.get_iter_at_line(line);
+        let lists = self.lists_at(&iter);
+        let tag = match lists.last() {
+            Some(&(ref tag, _)) => tag.clone(),
+            None => return,
+        };
+        let mut marker_end = self.marker_end(&iter);
+        let task = self.is_task(&marker_end);
+        let mut content = marker_end.clone();
+        if task {
+            content.forward_char();
+        }
+        if content.ends_line() {
+            // A new line after an empty item leaves the list instead
+            content.forward_char();
+            self.text.delete(&mut marker_end, &mut content);
+            self.outdent_item(line);
+        } else {
+            let (start, end) = line_bounds(&self.text.get_iter_at_line(line + 1));
+            for &(ref tag, _) in &lists {
+                self.text.apply_tag(tag, &start, &end);
+            }
+            let marker = list_marker(lists.last().unwrap().1.start, 0);
+            self.insert_marker(line + 1, &marker, &lists);
+            // Items after a task are tasks too
+            if task {
+                self.task(&mut self.marker_end(&self.text.get_iter_at_line(line + 1)), false);
+            }
+            self.update_markers(&tag);
+        }
+    }
+    /// Nest the list item on `line` inside the item before it
+    pub fn indent_item(&self, line: i32) {
+        let lists = self.lists_at(&self.text.get_iter_at_line(line));
+        let (tag, info) = match lists.last() {
+            Some(list) => list.clone(),
+            None => return,
+        };
+        // The first item of a list has nothing to be nested in
+        if line == 0 {
+            return;
+        }
+        let above = self.lists_at(&self.text.get_iter_at_line(line - 1));
+        if !above.iter().any(|&(ref other, _)| *other == tag) {
+            return;
+        }
+        let nested = match above.into_iter().find(|&(_, ref other)| other.depth == info.depth + 1) {
+            Some((nested, _)) => nested,
+            None => self.list_tag(info.depth + 1, info.start.map(|_| 1), true),
+        };
+        let (start, end) = line_bounds(&self.text.get_iter_at_line(line));
+        self.text.apply_tag(&nested, &start, &end);
+        self.update_markers(&tag);
+        self.update_markers(&nested);
+    }
+    /// Move the list item on `line` out of its innermost list
+    pub fn outdent_item(&self, line: i32) {
+        let lists = self.lists_at(&self.text.get_iter_at_line(line));
+        let tag = match lists.last() {
+            Some(&(ref tag, _)) => tag.clone(),
+            None => return,
+        };
+        let (start, end) = line_bounds(&self.text.get_iter_at_line(line));
+        self.text.remove_tag(&tag, &start, &end);
+        self.update_markers(&tag);
+        if lists.len() > 1 {
+            self.update_markers(&lists[lists.len() - 2].0);
+        } else {
+            let mut start = self.text.get_iter_at_line(line);
+            let mut marker_end = self.marker_end(&start);
+            self.text.delete(&mut start, &mut marker_end);
+        }
+    }
+    /// Get the heading level of the line `iter` is on
+    pub fn heading_at(&self, iter: &TextIter) -> Option<usize> {
+        let table = self.text.get_tag_table().unwrap();
+        let mut line_start = iter.clone();
+        line_start.set_line_offset(0);
+        (1..7).find(|level| line_start.has_tag(&table.lookup(&format!("h{}", level)).unwrap()))
+    }
+    /// Get the heading level of the line the cursor is on
+    pub fn heading(&self) -> Option<usize> {
+        let cursor = self.text.get_iter_at_mark(&self.text.get_insert().unwrap());
+        self.heading_at(&cursor)
+    }
+    /// Set the heading level of the selected lines, or make them plain text
+    pub fn set_heading(&self, level: Option<usize>) {
+        let cursor = self.text.get_iter_at_mark(&self.text.get_insert().unwrap());
+        let (mut start, mut end) = self.text
+            .get_selection_bounds()
+            .unwrap_or_else(|| (cursor.clone(), cursor));
+        start.set_line_offset(0);
+        if !end.ends_line() {
+            end.forward_to_line_end();
+        }
+        for other in 1..7 {
+            self.text.remove_tag_by_name(&format!("h{}", other), &start, &end);
+        }
+        if let Some(level) = level {
+            self.text.apply_tag_by_name(&format!("h{}", level), &start, &end);
+        }
+    }
+    /// Mark the text between `start` and `end` as a code block with the info string given
+    pub fn code_block(&self, info: &str, start: &TextIter, end: &TextIter) {
+        let tag = TextTag::new(None);
+        self.text.get_tag_table().unwrap().add(&tag);
+        self.text.apply_tag(&tag, start, end);
+        self.text.apply_tag_by_name("code", start, end);
+        self.code_blocks.lock().unwrap().insert(tag, info.to_owned());
+    }
+    /// Toggle code formatting on the selection, making a code block if it spans several lines
+    pub fn apply_code(&self, code: &TextTag) {
+        if let Some((mut start, mut end)) = self.text.get_selection_bounds() {
+            if start.get_line() == end.get_line() {
+                self.apply_plain_tag(code);
+                return;
+            }
+            start.set_line_offset(0);
+            if !end.ends_line() {
+                end.forward_to_line_end();
+            }
+            let existing = {
+                let code_blocks = self.code_blocks.lock().unwrap();
+                start
+                    .get_tags()
+                    .into_iter()
+                    .find(|tag| code_blocks.contains_key(tag))
+            };
+            if let Some(tag) = existing {
+                self.text.remove_tag(&tag, &start, &end);
+                self.text.remove_tag(code, &start, &end);
+            } else {
+                self.code_block("", &start, &end);
+            }
+        }
+    }
     pub fn apply_plain_tag(&self, tag: &TextTag) {
         if let Some((start, end)) = self.text.get_selection_bounds() {
             let mut iter = start.clone();
@@ -321,65 +2150,262 @@ impl View {
             }
         }
     }
-    pub fn save(&self, new_source: Source) -> Result<(), Error> {
-        let mut source = self.source.lock().unwrap();
-        if *source == Source::Unknown {
-            *source = new_source;
-        }
+    /// Read the contents of the buffer back as a document
+    pub fn document(&self) -> Document {
         let buffer = &self.text;
-        if let Some(mut writer) = source.writer() {
-            let mut iter = buffer.get_start_iter();
-            let table = buffer.get_tag_table().unwrap();
-            let bold = table.lookup("bold").unwrap();
-            let italic = table.lookup("italic").unwrap();
-            let h1 = table.lookup("h1").unwrap();
-            let h2 = table.lookup("h2").unwrap();
-            let link = table.lookup("link").unwrap();
-            let mut unclosed_tags = Vec::new();
-            let urls = self.image_urls.lock().unwrap();
-            loop {
-                let marks = iter.get_marks();
-                if let Some(url) = marks.into_iter().filter_map(|m| m.get_name()).next() {
-                    let start = iter.clone();
-                    iter.forward_to_tag_toggle(Some(&link));
-                    let text = buffer.get_slice(&start, &iter, false).unwrap();
-                    writer
-                        .write_all(format!("[{}]({})", text, url).as_bytes())?
-                } else if let Some(pixbuf) = iter.get_pixbuf() {
-                    let url = &urls[&pixbuf];
-                    writer.write_all(format!("![]({})\n", url).as_bytes())?
-                } else if iter.begins_tag(Some(&h1)) {
-                    writer.write_all(b"# ")?;
-                } else if iter.begins_tag(Some(&h2)) {
-                    writer.write_all(b"## ")?;
-                } else if iter.toggles_tag(Some(&bold)) {
-                    writer.write_all(b"**")?;
-                    if iter.begins_tag(Some(&bold)) {
-                        unclosed_tags.push("**");
-                    } else {
-                        debug_assert_eq!(unclosed_tags.pop(), Some("**"));
-                    }
-                } else if iter.toggles_tag(Some(&italic)) {
-                    writer.write_all(b"*")?;
-                    if iter.begins_tag(Some(&italic)) {
-                        unclosed_tags.push("*");
-                    } else {
-                        debug_assert_eq!(unclosed_tags.pop(), Some("*"));
+        let table = buffer.get_tag_table().unwrap();
+        let bold = table.lookup("bold").unwrap();
+        let italic = table.lookup("italic").unwrap();
+        let strikethrough = table.lookup("strikethrough").unwrap();
+        let link = table.lookup("link").unwrap();
+        let code = table.lookup("code").unwrap();
+        let html = table.lookup("html").unwrap();
+        let html_block = table.lookup("html-block").unwrap();
+        let hard_break = table.lookup("hard-break").unwrap();
+        let soft_break = table.lookup("soft-break").unwrap();
+        let item = table.lookup("item").unwrap();
+        let headers: Vec<TextTag> = (1..7)
+            .map(|level| table.lookup(&format!("h{}", level)).unwrap())
+            .collect();
+        let images = self.images.lock().unwrap();
+        let links = self.links.lock().unwrap();
+        let code_blocks = self.code_blocks.lock().unwrap();
+        let tables = self.tables.lock().unwrap();
+        let rules = self.rules.lock().unwrap();
+        let tasks = self.tasks.lock().unwrap();
+        let footnote_refs = self.footnote_refs.lock().unwrap();
+        let mut blocks = BlockBuilder::new();
+        let mut iter = buffer.get_start_iter();
+        // The link being read, which carries on until another starts
+        let mut link_info: Option<LinkInfo> = None;
+        // The code block being read, along with the containers it is in
+        let mut code_block: Option<(TextTag, Vec<Container>, Block)> = None;
+        // The HTML block being read, along with the containers it is in
+        let mut html_lines: Option<(Vec<Container>, String)> = None;
+        loop {
+            let containers: Vec<Container> = self.containers_at(&iter)
+                .into_iter()
+                .map(|(_, _, container)| container)
+                .collect();
+            let is_item = !containers.is_empty() && iter.has_tag(&item);
+            if is_item {
+                iter = self.marker_end(&iter);
+            }
+            let tag = iter.get_tags()
+                .into_iter()
+                .find(|tag| code_blocks.contains_key(tag));
+            // Lines of the same code block are joined, unless a new item starts between them
+            let same = !is_item &&
+                       match (&code_block, &tag) {
+                           (&Some((ref open, _, _)), &Some(ref tag)) => open == tag,
+                           _ => false,
+                       };
+            if !same {
+                if let Some((_, containers, block)) = code_block.take() {
+                    blocks.push(&containers, block);
+                }
+            }
+            // Lines of HTML are joined in the same way
+            let is_html = iter.has_tag(&html_block);
+            let same_html = !is_item && is_html &&
+                            html_lines
+                                .as_ref()
+                                .map_or(false, |&(ref open, _)| *open == containers);
+            if !same_html {
+                if let Some((containers, text)) = html_lines.take() {
+                    blocks.push(&containers, Block::Html(text));
+                }
+            }
+            if is_item {
+                blocks.item(&containers);
+            }
+            if let Some(tag) = tag {
+                let mut end = iter.clone();
+                if !end.ends_line() {
+                    end.forward_to_line_end();
+                }
+                let line = buffer.get_slice(&iter, &end, false).unwrap_or_default();
+                if same {
+                    if let Some((_, _, Block::CodeBlock { ref mut text, .. })) = code_block {
+                        text.push_str(&line);
+                        text.push('\n');
                     }
+                } else {
+                    let info = code_blocks[&tag].clone();
+                    code_block = Some((tag,
+                                       containers,
+                                       Block::CodeBlock {
+                                           info,
+                                           text: line + "\n",
+                                       }));
                 }
-                if let Some(ch) = iter.get_char() {
-                    if ch == '\n' {
-                        writer.write_all(b"\n")?;
+                iter = end;
+                if !iter.forward_line() {
+                    break;
+                }
+                continue;
+            }
+            if is_html {
+                let mut end = iter.clone();
+                if !end.ends_line() {
+                    end.forward_to_line_end();
+                }
+                let line = buffer.get_slice(&iter, &end, false).unwrap_or_default();
+                match html_lines {
+                    Some((_, ref mut text)) => {
+                        text.push_str(&line);
+                        text.push('\n');
                     }
-                    writer.write_all(&ch.to_string().as_bytes())?;
+                    None => html_lines = Some((containers, line + "\n")),
                 }
-                if !iter.forward_char() {
+                iter = end;
+                if !iter.forward_line() {
                     break;
                 }
+                continue;
             }
-            for tag in unclosed_tags {
-                writer.write_all(tag.as_bytes())?;
+            let table = iter.get_child_anchor().and_then(|anchor| tables.get(&anchor).cloned());
+            if let Some(table) = table {
+                blocks.push(&containers,
+                            Block::Table {
+                                alignments: table.alignments,
+                                rows: table.rows(),
+                            });
+                if !iter.forward_line() {
+                    break;
+                }
+                continue;
+            }
+            if iter.get_child_anchor().map_or(false, |anchor| rules.contains(&anchor)) {
+                blocks.push(&containers, Block::Rule);
+                iter.forward_char();
             }
+            let header = headers.iter().position(|h| iter.has_tag(h));
+            let mut inlines = InlineBuilder::new();
+            // Line breaks within the paragraph carry it on to the next line
+            while !iter.ends_line() || iter.has_tag(&hard_break) || iter.has_tag(&soft_break) {
+                let mut styles = Vec::with_capacity(4);
+                if iter.has_tag(&link) {
+                    if let Some(info) = iter.get_marks()
+                           .into_iter()
+                           .filter_map(|mark| links.get(&mark))
+                           .next() {
+                        link_info = Some(info.clone());
+                    }
+                    if let Some(ref info) = link_info {
+                        styles.push(Style::Link(info.url.clone(),
+                                                info.title.clone(),
+                                                info.reference.clone()));
+                    }
+                }
+                if iter.has_tag(&bold) {
+                    styles.push(Style::Strong);
+                }
+                if iter.has_tag(&italic) {
+                    styles.push(Style::Emphasis);
+                }
+                if iter.has_tag(&strikethrough) {
+                    styles.push(Style::Strikethrough);
+                }
+                if iter.has_tag(&code) {
+                    styles.push(Style::Code);
+                }
+                if iter.has_tag(&hard_break) {
+                    inlines.push(&styles, Inline::HardBreak);
+                } else if iter.has_tag(&soft_break) {
+                    inlines.push(&styles, Inline::SoftBreak);
+                } else if iter.has_tag(&html) {
+                    let mut end = iter.clone();
+                    end.forward_to_tag_toggle(Some(&html));
+                    let mut line_end = iter.clone();
+                    if !line_end.ends_line() {
+                        line_end.forward_to_line_end();
+                    }
+                    let end = min(end, line_end);
+                    let span = buffer.get_slice(&iter, &end, false).unwrap_or_default();
+                    inlines.push(&styles, Inline::Html(span));
+                    iter = end;
+                    continue;
+                } else if let Some(image) = iter.get_child_anchor()
+                              .and_then(|anchor| images.get(&anchor)) {
+                    let image = &image.info;
+                    inlines.push(&styles,
+                                 match image.html {
+                                     Some(ref html) => Inline::Html(html.clone()),
+                                     None => {
+                                         Inline::Image {
+                                             url: image.url.clone(),
+                                             title: image.title.clone(),
+                                             alt: image.alt.clone(),
+                                             reference: image.reference.clone(),
+                                         }
+                                     }
+                                 });
+                } else if let Some(check) = iter.get_child_anchor()
+                              .and_then(|anchor| tasks.get(&anchor)) {
+                    inlines.push(&styles, Inline::TaskMarker(check.get_active()));
+                } else if let Some(marker) = iter.get_child_anchor()
+                              .and_then(|anchor| footnote_refs.get(&anchor)) {
+                    let label = marker.get_text().unwrap_or_default();
+                    inlines.push(&styles, Inline::FootnoteReference(label));
+                } else if iter.get_child_anchor().is_none() {
+                    if let Some(ch) = iter.get_char() {
+                        inlines.push_text(&styles, &ch.to_string());
+                    }
+                }
+                iter.forward_char();
+            }
+            let inlines = inlines.finish();
+            if !inlines.is_empty() {
+                blocks.push(&containers,
+                            match header {
+                                Some(index) => Block::Header(index as i32 + 1, inlines),
+                                None => Block::Paragraph(inlines),
+                            });
+            }
+            if !iter.forward_line() {
+                break;
+            }
+        }
+        if let Some((_, containers, block)) = code_block {
+            blocks.push(&containers, block);
+        }
+        if let Some((containers, text)) = html_lines {
+            blocks.push(&containers, Block::Html(text));
+        }
+        Document {
+            blocks: blocks.finish(),
+            definitions: self.definitions.lock().unwrap().clone(),
+        }
+    }
+    pub fn save(&self, new_source: Source) -> Result<(), Error> {
+        let mut source = self.source.lock().unwrap();
+        if *source == Source::Unknown {
+            *source = new_source;
+        }
+        if source.can_save() {
+            let mut document = self.document();
+            let mut original = self.original.lock().unwrap();
+            let unedited = original
+                .as_ref()
+                .and_then(|original| original.unedited(&document, self.text.get_modified()))
+                .map(str::to_owned);
+            let text = match unedited {
+                Some(text) => text,
+                None => {
+                    let renamed = document.collect_footnotes();
+                    self.rename_footnotes(&renamed);
+                    document.to_markdown()
+                }
+            };
+            let format = *self.format.lock().unwrap();
+            let bytes = format
+                .encode(&text)
+                .map_err(|c| Error::Unencodable(c, format.encoding))?;
+            source.save(&bytes,
+                        *self.backup.lock().unwrap(),
+                        &mut self.remote.lock().unwrap())?;
+            *original = Some(Original { text, document });
         }
         self.text.set_modified(false);
         Ok(())
@@ -396,3 +2422,66 @@ impl View {
         format!("{}{}", title, symbol)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_list_markers_start_lists() {
+        assert_eq!(list_prefix("- item"), Some((None, 2)));
+        assert_eq!(list_prefix("+ item"), Some((None, 2)));
+        assert_eq!(list_prefix("12. item"), Some((Some(12), 4)));
+        assert_eq!(list_prefix("3) item"), Some((Some(3), 3)));
+        // Nothing has been typed into the item yet
+        assert_eq!(list_prefix("- "), None);
+        assert_eq!(list_prefix("-item"), None);
+        assert_eq!(list_prefix("1234567890. item"), None);
+        assert_eq!(list_marker(None, 4), "• ");
+        assert_eq!(list_marker(Some(3), 2), "5. ");
+    }
+
+    #[test]
+    fn images_are_read_from_img_tags() {
+        let image = ImageInfo::from_html("<IMG src=\"a b.png\" alt='x &amp; y' title=t \
+                                          width=\"40px\" height=0 />\n")
+                .unwrap();
+        assert_eq!(image.url, "a b.png");
+        assert_eq!(image.alt, "x & y");
+        assert_eq!(image.title, "t");
+        assert_eq!(image.width, Some(40));
+        assert_eq!(image.height, None);
+        assert_eq!(image.html.as_ref().map(String::as_str),
+                   Some("<IMG src=\"a b.png\" alt='x &amp; y' title=t width=\"40px\" height=0 />"));
+        assert!(ImageInfo::from_html("<img alt=\"no source\">").is_none());
+        assert!(ImageInfo::from_html("<img src=a.png><br>").is_none());
+        assert!(ImageInfo::from_html("<imgs src=a.png>").is_none());
+    }
+
+    #[test]
+    fn image_tooltips_show_errors_then_titles_then_alt_text() {
+        let mut image = ImageInfo {
+            alt: "alt".to_owned(),
+            ..ImageInfo::default()
+        };
+        assert_eq!(image.tooltip(), Some("alt"));
+        image.title = "title".to_owned();
+        assert_eq!(image.tooltip(), Some("title"));
+        image.error = Some("Not found".to_owned());
+        assert_eq!(image.tooltip(), Some("Not found"));
+        assert_eq!(ImageInfo::default().tooltip(), None);
+    }
+
+    #[test]
+    fn images_are_sized_as_asked_within_the_view() {
+        assert_eq!(fit_size((200, 100), (None, None), 500, 1.), (200, 100));
+        assert_eq!(fit_size((200, 100), (Some(50), Some(50)), 500, 1.), (50, 50));
+        // The aspect ratio is kept when only one side is given
+        assert_eq!(fit_size((200, 100), (Some(100), None), 500, 1.), (100, 50));
+        assert_eq!(fit_size((200, 100), (None, Some(20)), 500, 1.), (40, 20));
+        assert_eq!(fit_size((1000, 500), (None, None), 500, 1.), (500, 250));
+        // Zooming goes past the width of the view
+        assert_eq!(fit_size((1000, 500), (None, None), 500, 1.25), (625, 312));
+        assert_eq!(fit_size((200, 100), (None, None), 500, 0.001), (1, 1));
+    }
+}